@@ -1,17 +1,104 @@
-use crate::util::{ProgressBar as PB, ProgressBarFactory as PBF};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::transport::Transport;
+use crate::util::{track, ProgressBar as PB, ProgressBarFactory as PBF};
 use crate::{QoobError, QoobResult};
 
 const HID_BUFFER_SIZE: usize = 65;
 const DATA_TRANSFER_UNIT: usize = 63;
-const MAX_TRANSFER_SIZE: usize = 32 * 1024;
+/// How many times a short transfer is retried before giving up, by default
+const DEFAULT_RETRIES: usize = 3;
+/// How often to look for a device while waiting for one to be connected
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait before retrying a short transfer
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+/// How long to wait between the first status queries while polling for completion
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// The longest to wait between status queries by default, see [`QoobDevice::set_poll_interval`]
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait for a sector erase to complete by default
+const ERASE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the bus to be acquired or released by default
+const BUS_TIMEOUT: Duration = Duration::from_secs(2);
+/// The largest read or write the device accepts in a single command
+///
+/// The length field of a command is 16 bits wide,
+/// but the Windows flasher never goes beyond 32KiB, so neither do we.
+pub const MAX_TRANSFER_SIZE: usize = 32 * 1024;
 
-/// The size of a single flash sector
+/// The size of a single flash sector on a Qoob Pro, see [`Geometry`] for other chips
 pub const SECTOR_SIZE: usize = 64 * 1024;
-/// The total number of sectors in flash
+/// The total number of sectors in a Qoob Pro's flash
 pub const SECTOR_COUNT: usize = 32;
-/// The total size of flash ([`SECTOR_SIZE`] * [`SECTOR_COUNT`])
+/// The total size of a Qoob Pro's flash ([`SECTOR_SIZE`] * [`SECTOR_COUNT`])
 pub const FLASH_SIZE: usize = SECTOR_COUNT * SECTOR_SIZE;
 
+/// The layout of a flash chip
+///
+/// Defaults to that of the Qoob Pro, the only one supported so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+	/// The size of a single sector, the smallest unit that can be erased
+	pub sector_size: usize,
+	/// The number of sectors
+	pub sector_count: usize,
+}
+
+impl Default for Geometry {
+	fn default() -> Self {
+		Self {
+			sector_size: SECTOR_SIZE,
+			sector_count: SECTOR_COUNT,
+		}
+	}
+}
+
+impl Geometry {
+	/// The total size of flash
+	pub fn flash_size(&self) -> usize {
+		self.sector_size * self.sector_count
+	}
+
+	/// How many sectors `size` would span
+	pub fn size_to_sectors(&self, size: usize) -> usize {
+		size.div_ceil(self.sector_size)
+	}
+
+	/// The sector a byte offset falls into
+	pub fn offset_to_sector(&self, offset: usize) -> usize {
+		offset / self.sector_size
+	}
+
+	/// The byte offset at which a sector starts
+	pub fn sector_to_offset(&self, sector: usize) -> usize {
+		sector * self.sector_size
+	}
+
+	/// Fail with [`QoobError::OutOfBounds`] if `len` bytes at `offset` don't fit in flash
+	pub fn check_bounds(&self, offset: usize, len: usize) -> QoobResult<()> {
+		if offset
+			.checked_add(len)
+			.is_none_or(|end| end > self.flash_size())
+		{
+			return Err(QoobError::OutOfBounds { offset, len });
+		}
+		Ok(())
+	}
+
+	/// Fail with [`QoobError::OutOfBounds`] if a range of sectors doesn't fit in flash
+	pub fn check_sectors(&self, sectors: std::ops::Range<usize>) -> QoobResult<()> {
+		if sectors.start > sectors.end || sectors.end > self.sector_count {
+			let len = sectors.end.saturating_sub(sectors.start);
+			return Err(QoobError::OutOfBounds {
+				offset: sectors.start.saturating_mul(self.sector_size),
+				len: len.saturating_mul(self.sector_size),
+			});
+		}
+		Ok(())
+	}
+}
+
 #[repr(u8)]
 enum QoobCmd {
 	Reset = 1,
@@ -22,9 +109,140 @@ enum QoobCmd {
 	Bus = 8,
 }
 
+/// The device's state, as returned by [`QoobDevice::status_info`]
+///
+/// Only the fields whose meaning has been observed are decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceStatus([u8; HID_BUFFER_SIZE]);
+
+impl DeviceStatus {
+	/// Whether a sector erase is still in progress
+	pub fn erasing(&self) -> bool {
+		self.0[2] != 0
+	}
+
+	/// Whether we hold the flash bus
+	pub fn bus_held(&self) -> bool {
+		self.0[4] == 0
+	}
+
+	/// Whether the bus has been handed back to the GameCube
+	pub fn bus_released(&self) -> bool {
+		self.0[4] == 1
+	}
+
+	/// Whether the GameCube is using the bus, so it can't be acquired
+	pub fn bus_busy(&self) -> bool {
+		self.0[4] & 2 != 0
+	}
+
+	/// The whole report, report ID included
+	pub fn raw(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+/// What to do when a sector fails to erase, see [`QoobDevice::erase_with_policy`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BadSectorAction {
+	/// Stop and return the error
+	Abort,
+	/// Leave the sector as is and carry on with the next one
+	Skip,
+}
+
+/// A step of [`QoobDevice::selftest`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTestStep {
+	/// Erase the sector
+	Erase,
+	/// Fill it with a test pattern
+	Write,
+	/// Read the pattern back and compare it
+	Verify,
+	/// Erase the sector again and check that it's blank
+	Cleanup,
+}
+
+impl SelfTestStep {
+	pub fn str(&self) -> &'static str {
+		match self {
+			Self::Erase => "erase",
+			Self::Write => "write",
+			Self::Verify => "verify",
+			Self::Cleanup => "cleanup",
+		}
+	}
+}
+
+/// The outcome of [`QoobDevice::selftest`]
+#[derive(Debug)]
+pub struct SelfTestReport {
+	/// The steps that ran, in order, along with how they went
+	///
+	/// Steps after a failure are skipped, except for [`SelfTestStep::Cleanup`].
+	pub steps: Vec<(SelfTestStep, QoobResult<()>)>,
+}
+
+impl SelfTestReport {
+	/// Whether every step succeeded
+	pub fn passed(&self) -> bool {
+		self.steps.iter().all(|(_, result)| result.is_ok())
+	}
+}
+
+/// Criteria used to find a device
+///
+/// Fields left as `None` match anything.
+/// The default matches genuine Qoob Pro chips,
+/// other values can be used for clones that identify differently.
+#[derive(Clone, Debug)]
+pub struct DeviceFilter {
+	pub vendor_id: Option<u16>,
+	pub product_id: Option<u16>,
+	pub manufacturer: Option<String>,
+	pub product: Option<String>,
+}
+
+impl Default for DeviceFilter {
+	fn default() -> Self {
+		Self {
+			vendor_id: Some(0x03eb),  // Atmel Corp.
+			product_id: Some(0x0001), // Not listed in usb.ids
+			manufacturer: Some(String::from("QooB Team")),
+			product: Some(String::from("QOOB Chip Pro")),
+		}
+	}
+}
+
+impl DeviceFilter {
+	fn matches(&self, info: &hidapi::DeviceInfo) -> bool {
+		fn check<T: PartialEq>(expected: Option<T>, actual: T) -> bool {
+			expected.is_none_or(|e| e == actual)
+		}
+
+		matches!(info.bus_type(), hidapi::BusType::Usb)
+			&& check(self.vendor_id, info.vendor_id())
+			&& check(self.product_id, info.product_id())
+			&& check(
+				self.manufacturer.as_deref(),
+				info.manufacturer_string().unwrap_or(""),
+			) && check(self.product.as_deref(), info.product_string().unwrap_or(""))
+	}
+}
+
 /// A handle to a connected Qoob
 pub struct QoobDevice {
-	hid_dev: hidapi::HidDevice,
+	transport: Box<dyn Transport>,
+	transfer_size: usize,
+	timeout: Option<Duration>,
+	retries: usize,
+	geometry: Geometry,
+	poll_interval: Duration,
+	/// How many times the bus has been acquired and not yet released
+	bus_holds: Cell<usize>,
+	drop_error_hook: Option<Box<dyn Fn(QoobError) + Send>>,
+	should_cancel: Option<Box<dyn Fn() -> bool + Send>>,
 }
 
 impl QoobDevice {
@@ -32,64 +250,283 @@ impl QoobDevice {
 	///
 	/// An error is raised if more than one is connected.
 	pub fn connect() -> QoobResult<Self> {
-		let api = hidapi::HidApi::new()?;
+		Self::connect_with_filter(DeviceFilter::default())
+	}
+
+	/// Connect to a device matching `filter`.
+	///
+	/// An error is raised if more than one is connected.
+	pub fn connect_with_filter(filter: DeviceFilter) -> QoobResult<Self> {
+		let mut api = hidapi::HidApi::new()?;
 
 		// Filter the list
-		let mut devs = api.device_list().filter(|info| {
-			matches!(info.bus_type(), hidapi::BusType::Usb)
-				&& info.vendor_id() == 0x03eb // Atmel Corp.
-				&& info.product_id() == 0x0001 // Not listed in usb.ids
-				&& info.manufacturer_string() == Some("QooB Team")
-				&& info.product_string() == Some("QOOB Chip Pro")
-		});
+		let mut devs = api.device_list().filter(|info| filter.matches(info));
 
-		let dev = devs.next().ok_or(QoobError::NoDev)?;
+		let dev = devs.next().ok_or(QoobError::NoDev)?.clone();
 
 		if devs.next().is_some() {
 			return Err(QoobError::MultipleDevs);
 		}
+		drop(devs);
 
-		Ok(Self {
-			hid_dev: dev.open_device(&api)?,
-		})
+		let hid_dev = match dev.open_device(&api) {
+			Ok(hid_dev) => hid_dev,
+			Err(error) => {
+				// The device may have been unplugged since it was enumerated
+				api.refresh_devices()?;
+				if api.device_list().all(|info| info.path() != dev.path()) {
+					return Err(QoobError::Disconnected);
+				}
+				return Err(error.into());
+			}
+		};
+
+		Ok(Self::from_transport(Box::new(hid_dev)))
+	}
+
+	/// Talk to a device through something other than a directly opened HID device
+	pub fn from_transport(transport: Box<dyn Transport>) -> Self {
+		Self {
+			transport,
+			transfer_size: MAX_TRANSFER_SIZE,
+			timeout: None,
+			retries: DEFAULT_RETRIES,
+			geometry: Geometry::default(),
+			poll_interval: DEFAULT_POLL_INTERVAL,
+			bus_holds: Cell::new(0),
+			drop_error_hook: None,
+			should_cancel: None,
+		}
+	}
+
+	/// Replace the transport with one built around it, e.g. to log traffic
+	pub fn map_transport(
+		mut self,
+		wrap: impl FnOnce(Box<dyn Transport>) -> Box<dyn Transport>,
+	) -> Self {
+		self.transport = wrap(self.transport);
+		self
+	}
+
+	/// Connect to the device, waiting up to `timeout` for it to be plugged in.
+	///
+	/// An error is raised if more than one is connected.
+	pub fn connect_wait(timeout: Duration) -> QoobResult<Self> {
+		Self::connect_with_filter_wait(DeviceFilter::default(), timeout)
+	}
+
+	/// Connect to a device matching `filter`, waiting up to `timeout` for it to be plugged in.
+	///
+	/// An error is raised if more than one is connected.
+	pub fn connect_with_filter_wait(filter: DeviceFilter, timeout: Duration) -> QoobResult<Self> {
+		let deadline = Instant::now() + timeout;
+		loop {
+			match Self::connect_with_filter(filter.clone()) {
+				Err(QoobError::NoDev) if Instant::now() < deadline => {
+					std::thread::sleep(CONNECT_POLL_INTERVAL);
+				}
+				result => return result,
+			}
+		}
+	}
+
+	/// Set the size of the chunks bulk reads and writes are split into.
+	///
+	/// Clamped to `1..=`[`MAX_TRANSFER_SIZE`].
+	/// Each chunk is a separate command,
+	/// so smaller chunks mean more overhead but shorter individual transfers.
+	pub fn set_transfer_size(&mut self, size: usize) {
+		self.transfer_size = size.clamp(1, MAX_TRANSFER_SIZE);
+	}
+
+	/// The size of the chunks bulk reads and writes are split into
+	pub fn transfer_size(&self) -> usize {
+		self.transfer_size
+	}
+
+	/// Set how long to wait for the device to finish an operation before giving up.
+	///
+	/// Applies to bus acquisition and release, and sector erases.
+	/// By default, erases get 10 seconds and bus operations get 2.
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = Some(timeout);
+	}
+
+	/// Set what to do with errors that happen while releasing the bus from a [`BusGuard`]'s `Drop`
+	///
+	/// By default, they're printed to stderr.
+	/// Use [`BusGuard::release`] or [`QoobDevice::close`] to get them as a [`QoobResult`] instead.
+	pub fn on_drop_error(&mut self, hook: Box<dyn Fn(QoobError) + Send>) {
+		self.drop_error_hook = Some(hook);
+	}
+
+	/// Set a check for whether to abandon long operations
+	///
+	/// It's called between chunks of bulk reads and writes, and between sector erases.
+	/// Once it returns `true`, the operation stops with [`QoobError::Cancelled`]
+	/// and the bus is released.
+	pub fn set_cancel_check(&mut self, should_cancel: Box<dyn Fn() -> bool + Send>) {
+		self.should_cancel = Some(should_cancel);
+	}
+
+	/// Fail with [`QoobError::Cancelled`] if cancellation was requested
+	fn check_cancel(&self, offset: usize) -> QoobResult<()> {
+		match &self.should_cancel {
+			Some(should_cancel) if should_cancel() => Err(QoobError::Cancelled { offset }),
+			_ => Ok(()),
+		}
 	}
 
-	fn send_buffer(&self, buf: &[u8; HID_BUFFER_SIZE]) -> QoobResult<()> {
+	/// Release the bus and disconnect, reporting whether the release succeeded
+	///
+	/// The bus is normally only held for the duration of an operation,
+	/// but this makes sure the GameCube isn't left locked out.
+	pub fn close(self) -> QoobResult<()> {
+		self.release_bus()
+	}
+
+	/// The layout of the flash chip
+	pub fn geometry(&self) -> Geometry {
+		self.geometry
+	}
+
+	/// Set the layout of the flash chip, for devices other than the Qoob Pro
+	///
+	/// Must be done before mounting a [`QoobFs`](crate::QoobFs),
+	/// which sizes its sector map from it.
+	pub fn set_geometry(&mut self, geometry: Geometry) {
+		self.geometry = geometry;
+	}
+
+	/// Set how many times a short transfer is retried before failing with
+	/// [`QoobError::PartialTransfer`]
+	///
+	/// Defaults to 3.
+	pub fn set_retries(&mut self, retries: usize) {
+		self.retries = retries;
+	}
+
+	/// Set the longest delay between status queries while waiting for an operation to complete
+	///
+	/// Polling starts every millisecond and backs off up to this interval.
+	/// Defaults to 10ms.
+	pub fn set_poll_interval(&mut self, interval: Duration) {
+		self.poll_interval = interval;
+	}
+
+	/// When to give up waiting on an operation that defaults to timing out after `default`
+	fn deadline(&self, default: Duration) -> Instant {
+		Instant::now() + self.timeout.unwrap_or(default)
+	}
+
+	/// Send a report, `operation` being what it's part of for error reporting
+	fn send_buffer(&self, buf: &[u8; HID_BUFFER_SIZE], operation: &'static str) -> QoobResult<()> {
 		// Report ID is always 0
 		assert_eq!(buf[0], 0);
-		let transferred = self.hid_dev.write(buf)?;
-		if transferred != buf.len() {
-			Err(QoobError::PartialTransfer {
-				transferred,
-				requested: buf.len(),
-			})
-		} else {
-			Ok(())
+		let mut attempts = 0;
+		loop {
+			// A report can't be sent piecemeal, so a short write means sending it again
+			let transferred = self.transport.write(buf)?;
+			if transferred == buf.len() {
+				return Ok(());
+			}
+			attempts += 1;
+			if attempts > self.retries {
+				return Err(QoobError::PartialTransfer {
+					operation,
+					transferred,
+					requested: buf.len(),
+				});
+			}
+			std::thread::sleep(RETRY_DELAY);
 		}
 	}
 
-	fn receive_buffer(&self) -> QoobResult<[u8; HID_BUFFER_SIZE]> {
+	/// Receive a report, `operation` being what it's part of for error reporting
+	fn receive_buffer(&self, operation: &'static str) -> QoobResult<[u8; HID_BUFFER_SIZE]> {
 		let mut buf = [0; HID_BUFFER_SIZE];
+		self.receive_into(&mut buf, operation)?;
+		Ok(buf)
+	}
+
+	/// Receive a report into an existing buffer, see [`QoobDevice::receive_buffer`]
+	///
+	/// Lets hot loops reuse one buffer instead of setting up a new one for every report.
+	fn receive_into(
+		&self,
+		buf: &mut [u8; HID_BUFFER_SIZE],
+		operation: &'static str,
+	) -> QoobResult<()> {
 		// Report ID is always 0
-		assert_eq!(buf[0], 0);
-		let transferred = self.hid_dev.get_feature_report(&mut buf)?;
-		if transferred != buf.len() {
-			Err(QoobError::PartialTransfer {
-				transferred,
-				requested: buf.len(),
-			})
-		} else {
-			Ok(buf)
+		buf[0] = 0;
+		let mut attempts = 0;
+		loop {
+			let transferred = self.transport.get_feature_report(buf)?;
+			if transferred == buf.len() {
+				return Ok(());
+			}
+			attempts += 1;
+			if attempts > self.retries {
+				return Err(QoobError::PartialTransfer {
+					operation,
+					transferred,
+					requested: buf.len(),
+				});
+			}
+			std::thread::sleep(RETRY_DELAY);
+		}
+	}
+
+	/// Query the device's status until `done` says the operation is over
+	///
+	/// Fails with [`QoobError::Timeout`] if that takes longer than `timeout`.
+	fn poll(
+		&self,
+		timeout: Duration,
+		operation: &'static str,
+		mut done: impl FnMut(&DeviceStatus) -> QoobResult<bool>,
+	) -> QoobResult<()> {
+		let deadline = self.deadline(timeout);
+		let mut delay = MIN_POLL_INTERVAL.min(self.poll_interval);
+		loop {
+			if done(&self.status_info()?)? {
+				return Ok(());
+			}
+			if Instant::now() > deadline {
+				return Err(QoobError::Timeout { operation });
+			}
+			std::thread::sleep(delay);
+			delay = (delay * 2).min(self.poll_interval);
 		}
 	}
 
 	/// Query the device's status.
-	fn status(&self) -> QoobResult<[u8; HID_BUFFER_SIZE]> {
+	pub fn status_info(&self) -> QoobResult<DeviceStatus> {
 		let mut buf = [0; HID_BUFFER_SIZE];
 		buf[1] = QoobCmd::Status as _;
-		self.send_buffer(&buf)?;
+		self.send_buffer(&buf, "status query")?;
 
-		self.receive_buffer()
+		Ok(DeviceStatus(self.receive_buffer("status query")?))
+	}
+
+	/// Check that the device is still responding.
+	///
+	/// Cheap enough to use as a health check before a long operation,
+	/// or to notice a stale handle, e.g. after the host resumed from sleep.
+	///
+	/// ```no_run
+	/// # use std::time::Duration;
+	/// let qoob = rqoob::QoobDevice::connect()?;
+	/// // Keep an eye on an idle connection
+	/// loop {
+	///     qoob.ping()?;
+	///     std::thread::sleep(Duration::from_secs(5));
+	/// }
+	/// # Ok::<(), rqoob::QoobError>(())
+	/// ```
+	pub fn ping(&self) -> QoobResult<()> {
+		self.status_info()?;
+		Ok(())
 	}
 
 	/// Reset the device.
@@ -100,7 +537,7 @@ impl QoobDevice {
 	pub fn reset(self) -> QoobResult<()> {
 		let mut buf = [0; HID_BUFFER_SIZE];
 		buf[1] = QoobCmd::Reset as _;
-		self.send_buffer(&buf)
+		self.send_buffer(&buf, "reset")
 	}
 
 	/// Acquire some kind of lock.
@@ -108,42 +545,77 @@ impl QoobDevice {
 	/// Flash access will not work without this.
 	/// This is to protect against concurrent access by the GameCube.
 	/// The GC can't access flash while the bus is held.
+	///
+	/// Calls nest: only the outermost one talks to the device,
+	/// and the bus stays held until the matching [`QoobDevice::release_bus`].
 	pub(crate) fn get_bus(&self) -> QoobResult<()> {
+		if self.bus_holds.get() == 0 {
+			self.acquire_bus()?;
+		}
+		self.bus_holds.set(self.bus_holds.get() + 1);
+		Ok(())
+	}
+
+	/// Ask the device for the bus, regardless of whether we already hold it
+	fn acquire_bus(&self) -> QoobResult<()> {
 		let mut buf = [0; HID_BUFFER_SIZE];
 		buf[1] = QoobCmd::Bus as _;
 		buf[3] = 1;
-		self.send_buffer(&buf)?;
+		self.send_buffer(&buf, "bus acquisition")?;
 
-		loop {
-			let status = self.status()?[4];
-			if status == 0 {
-				return Ok(());
-			}
-			if status & 2 != 0 {
+		self.poll(BUS_TIMEOUT, "bus acquisition", |status| {
+			if status.bus_busy() {
 				return Err(QoobError::BusBusy);
 			}
-		}
+			Ok(status.bus_held())
+		})
+	}
+
+	/// Acquire the bus, holding it until the returned guard is dropped
+	///
+	/// Lets several operations run without giving the bus back in between.
+	/// Guards can be nested, e.g. by calling [`QoobDevice::read`] while holding one:
+	/// the bus is only released once the last of them is gone.
+	/// See the `_locked` variants of [`QoobDevice::read`], [`QoobDevice::write`] and
+	/// [`QoobDevice::erase`].
+	pub fn lock(&self) -> QoobResult<BusGuard<'_>> {
+		self.get_bus()?;
+		Ok(BusGuard { dev: self })
 	}
 
 	/// Release the bus lock.
+	///
+	/// Only gives the bus back once every [`QoobDevice::get_bus`] has been matched.
+	/// Called with the bus not held, e.g. from [`QoobDevice::close`], it releases it anyway.
 	pub(crate) fn release_bus(&self) -> QoobResult<()> {
+		let holds = self.bus_holds.get().saturating_sub(1);
+		self.bus_holds.set(holds);
+		if holds > 0 {
+			return Ok(());
+		}
+
 		let mut buf = [0; HID_BUFFER_SIZE];
 		buf[1] = QoobCmd::Bus as _;
 		buf[3] = 0;
-		self.send_buffer(&buf)?;
+		self.send_buffer(&buf, "bus release")?;
 
-		loop {
-			let status = self.status()?[4];
-			if status == 1 {
-				return Ok(());
-			}
-		}
+		self.poll(BUS_TIMEOUT, "bus release", |status| {
+			Ok(status.bus_released())
+		})
 	}
 
 	/// Read up to [`MAX_TRANSFER_SIZE`] bytes from flash.
 	pub(crate) fn read_raw(&self, offset: usize, dest: &mut [u8], pb: &impl PB) -> QoobResult<()> {
+		self.geometry.check_bounds(offset, dest.len())?;
+		self.read_command(offset, dest, pb)
+	}
+
+	/// Issue a read command without checking the range against the flash size.
+	///
+	/// The device takes 24-bit addresses.
+	fn read_command(&self, offset: usize, dest: &mut [u8], pb: &impl PB) -> QoobResult<()> {
 		assert!(dest.len() <= MAX_TRANSFER_SIZE);
-		assert!(offset + dest.len() <= FLASH_SIZE);
+		assert!(offset + dest.len() <= 1 << 24);
 
 		let mut buf = [0; HID_BUFFER_SIZE];
 		buf[1] = QoobCmd::Read as _;
@@ -155,34 +627,151 @@ impl QoobDevice {
 		buf[5] = (dest.len() >> 8) as u8;
 		buf[6] = dest.len() as u8;
 
-		self.send_buffer(&buf)?;
+		self.send_buffer(&buf, "read")?;
 
+		// Feature reports are synchronous, so the copy can't overlap the next transfer,
+		// but there's no need to set up a fresh buffer for each of them
 		for chunk in dest.chunks_mut(DATA_TRANSFER_UNIT) {
-			let buf = self.receive_buffer()?;
+			self.receive_into(&mut buf, "read")?;
 			chunk.copy_from_slice(&buf[2..2 + chunk.len()]);
 			pb.inc(chunk.len());
 		}
 		Ok(())
 	}
 
-	/// Read data from flash
-	pub fn read(&self, offset: usize, dest: &mut [u8], pbf: &impl PBF) -> QoobResult<()> {
-		assert!(offset + dest.len() <= FLASH_SIZE);
-		let pb = pbf.create(dest.len(), "Reading", None);
-		self.get_bus()?;
+	/// Check that the flash chip is the expected size.
+	///
+	/// Compares the start of every sector with what's found [flash size](Geometry::flash_size)
+	/// bytes further.
+	/// On a bigger chip, they'll hit different memory, which is reported as
+	/// [`QoobError::UnsupportedGeometry`].
+	/// A bigger chip whose halves have identical contents (e.g. both blank)
+	/// can't be told apart this way.
+	///
+	/// This relies on an unverified assumption: that on a chip of the expected size,
+	/// address bits above its size are ignored, so the second read wraps around to the same
+	/// memory as the first.
+	/// It's what a chip with fewer address lines than the 24-bit addresses the device takes
+	/// would do, but neither the firmware's behavior nor the board's wiring is documented,
+	/// and it hasn't been tried on hardware.
+	/// If it doesn't hold, the out-of-range read either fails, returning that error,
+	/// or returns something else, reported as [`QoobError::UnsupportedGeometry`]:
+	/// a wrong assumption makes the check fail, never pass.
+	/// That's why it isn't done on connection, see `--check-geometry` in the CLI.
+	pub fn check_geometry(&self) -> QoobResult<()> {
+		const PROBE_SIZE: usize = 16;
+		let guard = self.lock()?;
+		for sector in 0..self.geometry.sector_count {
+			let offset = self.geometry.sector_to_offset(sector);
+			let mut low = [0; PROBE_SIZE];
+			let mut high = [0; PROBE_SIZE];
+			self.read_command(offset, &mut low, &())?;
+			self.read_command(offset + self.geometry.flash_size(), &mut high, &())?;
+			if low != high {
+				guard.release()?;
+				return Err(QoobError::UnsupportedGeometry);
+			}
+		}
+		guard.release()
+	}
+
+	/// Read data from flash, split into transfer-sized chunks.
+	///
+	/// The bus must be held.
+	pub(crate) fn read_chunked(
+		&self,
+		offset: usize,
+		dest: &mut [u8],
+		pb: &impl PB,
+	) -> QoobResult<()> {
+		self.geometry.check_bounds(offset, dest.len())?;
 		let mut cursor = offset;
-		for chunk in dest.chunks_mut(MAX_TRANSFER_SIZE) {
-			self.read_raw(cursor, chunk, &pb)?;
+		for chunk in dest.chunks_mut(self.transfer_size) {
+			self.check_cancel(cursor)?;
+			self.read_raw(cursor, chunk, pb)?;
 			cursor += chunk.len();
 		}
-		self.release_bus()?;
-		pb.finish();
 		Ok(())
 	}
 
+	/// Read data from flash
+	pub fn read(&self, offset: usize, dest: &mut [u8], pbf: &impl PBF) -> QoobResult<()> {
+		let guard = self.lock()?;
+		self.read_locked(&guard, offset, dest, pbf)?;
+		guard.release()
+	}
+
+	/// Read data from flash while already holding the bus
+	pub fn read_locked(
+		&self,
+		guard: &BusGuard,
+		offset: usize,
+		dest: &mut [u8],
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		guard.check(self);
+		track(pbf.create(dest.len(), "Reading", None), |pb| {
+			self.read_chunked(offset, dest, pb)
+		})
+	}
+
+	/// Read data from flash, streaming it to `dest` instead of buffering it all in memory
+	pub fn read_to_writer(
+		&self,
+		offset: usize,
+		len: usize,
+		dest: &mut impl std::io::Write,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		self.geometry.check_bounds(offset, len)?;
+		track(pbf.create(len, "Reading", None), |pb| {
+			let guard = self.lock()?;
+			let mut buf = vec![0; self.transfer_size];
+			let mut cursor = offset;
+			while cursor < offset + len {
+				let chunk = &mut buf[..self.transfer_size.min(offset + len - cursor)];
+				self.check_cancel(cursor)?;
+				self.read_raw(cursor, chunk, pb)?;
+				dest.write_all(chunk)?;
+				cursor += chunk.len();
+			}
+			guard.release()
+		})
+	}
+
+	/// Check whether a sector is blank, i.e. entirely `0xFF`
+	///
+	/// Stops reading at the first byte that isn't.
+	pub fn is_blank(&self, sector: usize) -> QoobResult<bool> {
+		let guard = self.lock()?;
+		let blank = self.is_blank_locked(&guard, sector)?;
+		guard.release()?;
+		Ok(blank)
+	}
+
+	/// Check whether a sector is blank while already holding the bus
+	pub fn is_blank_locked(&self, guard: &BusGuard, sector: usize) -> QoobResult<bool> {
+		guard.check(self);
+		self.geometry
+			.check_sectors(sector..sector.saturating_add(1))?;
+		let mut buf = vec![0; self.transfer_size];
+		let end = self.geometry.sector_to_offset(sector + 1);
+		let mut cursor = self.geometry.sector_to_offset(sector);
+		while cursor < end {
+			let chunk = &mut buf[..self.transfer_size.min(end - cursor)];
+			self.read_raw(cursor, chunk, &())?;
+			if chunk.iter().any(|&b| b != 0xFF) {
+				return Ok(false);
+			}
+			cursor += chunk.len();
+		}
+		Ok(true)
+	}
+
 	/// Erase a sector
 	fn erase_raw(&self, sector: usize) -> QoobResult<()> {
-		assert!(sector < SECTOR_COUNT);
+		self.geometry
+			.check_sectors(sector..sector.saturating_add(1))?;
 		let mut buf = [0; HID_BUFFER_SIZE];
 		buf[1] = QoobCmd::Erase as _;
 		buf[2] = sector as u8;
@@ -191,35 +780,115 @@ impl QoobDevice {
 		// Regardless, the Windows flasher writes buf[3] as a 16 bit values, so let's preserve it.
 		buf[3] = 0;
 		buf[4] = 0;
-		self.send_buffer(&buf)?;
+		self.send_buffer(&buf, "erase")?;
 
-		loop {
-			let status = self.status()?[2];
-			if status == 0 {
-				return Ok(());
-			}
-		}
+		self.poll(ERASE_TIMEOUT, "erase", |status| Ok(!status.erasing()))
 	}
 
 	/// Erase a range of sectors
 	pub fn erase(&self, sectors: std::ops::Range<usize>, pbf: &impl PBF) -> QoobResult<()> {
-		assert!(sectors.start < SECTOR_COUNT);
-		assert!(sectors.end <= SECTOR_COUNT);
-		let pb = pbf.create(sectors.len(), "Erasing", Some(" sectors"));
-		self.get_bus()?;
+		self.erase_with_policy(sectors, |_, _| BadSectorAction::Abort, pbf)?;
+		Ok(())
+	}
+
+	/// Erase a range of sectors while already holding the bus
+	pub fn erase_locked(
+		&self,
+		guard: &BusGuard,
+		sectors: std::ops::Range<usize>,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		guard.check(self);
+		track(
+			pbf.create(sectors.len(), "Erasing", Some(" sectors")),
+			|pb| {
+				self.erase_sectors(sectors, |_, _| BadSectorAction::Abort, pb)?;
+				Ok(())
+			},
+		)
+	}
+
+	/// Erase the sectors of a range that aren't already blank
+	///
+	/// Saves time and wear when parts of the range are known to be clean.
+	/// Returns the sectors that actually needed erasing.
+	pub fn erase_if_dirty(
+		&self,
+		sectors: std::ops::Range<usize>,
+		pbf: &impl PBF,
+	) -> QoobResult<Vec<usize>> {
+		track(
+			pbf.create(sectors.len(), "Erasing", Some(" sectors")),
+			|pb| {
+				let guard = self.lock()?;
+				let mut erased = Vec::new();
+				for sector in sectors {
+					self.check_cancel(self.geometry.sector_to_offset(sector))?;
+					if !self.is_blank_locked(&guard, sector)? {
+						self.erase_raw(sector)?;
+						erased.push(sector);
+					}
+					pb.inc(1);
+				}
+				guard.release()?;
+				Ok(erased)
+			},
+		)
+	}
+
+	/// Erase a range of sectors, letting `policy` decide what to do when one fails
+	///
+	/// Returns the sectors that were skipped.
+	/// Moving data away from them, if desired, is up to the caller.
+	///
+	/// Only erase failures are handled this way.
+	/// Writes, and the [`QoobFs`](crate::QoobFs) operations built on them, still stop at the
+	/// first failure.
+	pub fn erase_with_policy(
+		&self,
+		sectors: std::ops::Range<usize>,
+		policy: impl FnMut(usize, &QoobError) -> BadSectorAction,
+		pbf: &impl PBF,
+	) -> QoobResult<Vec<usize>> {
+		track(
+			pbf.create(sectors.len(), "Erasing", Some(" sectors")),
+			|pb| {
+				let guard = self.lock()?;
+				let skipped = self.erase_sectors(sectors, policy, pb)?;
+				guard.release()?;
+				Ok(skipped)
+			},
+		)
+	}
+
+	/// Erase sectors one by one, applying `policy` to failures.
+	///
+	/// The bus must be held.
+	fn erase_sectors(
+		&self,
+		sectors: std::ops::Range<usize>,
+		mut policy: impl FnMut(usize, &QoobError) -> BadSectorAction,
+		pb: &impl PB,
+	) -> QoobResult<Vec<usize>> {
+		self.geometry.check_sectors(sectors.clone())?;
+		let mut skipped = Vec::new();
 		for sector in sectors {
-			self.erase_raw(sector)?;
+			self.check_cancel(self.geometry.sector_to_offset(sector))?;
+			if let Err(error) = self.erase_raw(sector) {
+				match policy(sector, &error) {
+					BadSectorAction::Abort => return Err(error),
+					BadSectorAction::Skip => skipped.push(sector),
+				}
+			}
 			pb.inc(1);
 		}
-		self.release_bus()?;
-		pb.finish();
-		Ok(())
+		Ok(skipped)
 	}
 
 	/// Write up to [`MAX_TRANSFER_SIZE`] bytes to flash.
 	fn write_raw(&self, offset: usize, source: &[u8], pb: &impl PB) -> QoobResult<()> {
 		assert!(source.len() <= MAX_TRANSFER_SIZE);
-		assert!(offset + source.len() <= FLASH_SIZE);
+		self.geometry.check_bounds(offset, source.len())?;
 
 		let mut buf = [0; HID_BUFFER_SIZE];
 		buf[1] = QoobCmd::Write as _;
@@ -231,34 +900,223 @@ impl QoobDevice {
 		buf[5] = (source.len() >> 8) as u8;
 		buf[6] = source.len() as u8;
 
-		self.send_buffer(&buf)?;
+		self.send_buffer(&buf, "write")?;
 
 		for chunk in source.chunks(DATA_TRANSFER_UNIT) {
 			let mut buf = [0; HID_BUFFER_SIZE];
 			buf[2..2 + chunk.len()].copy_from_slice(chunk);
-			self.send_buffer(&buf)?;
+			self.send_buffer(&buf, "write")?;
 			pb.inc(chunk.len());
 		}
 		Ok(())
 	}
 
-	/// Write data to flash
-	pub fn write(&self, offset: usize, source: &[u8], pbf: &impl PBF) -> QoobResult<()> {
-		assert!(offset + source.len() <= FLASH_SIZE);
-		let pb = pbf.create(source.len(), "Writing", None);
-		self.get_bus()?;
+	/// Write data to flash, split into transfer-sized chunks.
+	///
+	/// If `verify` is set, each chunk is read back and compared right after being written.
+	///
+	/// The bus must be held.
+	pub(crate) fn write_chunked(
+		&self,
+		offset: usize,
+		source: &[u8],
+		verify: bool,
+		pb: &impl PB,
+	) -> QoobResult<()> {
+		self.geometry.check_bounds(offset, source.len())?;
+		let mut verif_buf = vec![0; if verify { self.transfer_size } else { 0 }];
 		let mut cursor = offset;
-		for chunk in source.chunks(MAX_TRANSFER_SIZE) {
-			self.write_raw(cursor, chunk, &pb)?;
+		for chunk in source.chunks(self.transfer_size) {
+			self.check_cancel(cursor)?;
+			self.write_raw(cursor, chunk, pb)
+				.map_err(|error| QoobError::WriteFailed {
+					offset: cursor,
+					written: cursor - offset,
+					error: Box::new(error),
+				})?;
+			if verify {
+				let verif_chunk = &mut verif_buf[..chunk.len()];
+				self.read_raw(cursor, verif_chunk, &())?;
+				let mismatch = verif_chunk.iter().zip(chunk).position(|(a, e)| a != e);
+				if let Some(i) = mismatch {
+					return Err(QoobError::VerificationMismatch {
+						offset: cursor + i,
+						expected: chunk[i],
+						found: verif_chunk[i],
+					});
+				}
+			}
 			cursor += chunk.len();
 		}
-		self.release_bus()?;
-		pb.finish();
 		Ok(())
 	}
+
+	/// Write data to flash
+	pub fn write(&self, offset: usize, source: &[u8], pbf: &impl PBF) -> QoobResult<()> {
+		let guard = self.lock()?;
+		self.write_locked(&guard, offset, source, pbf)?;
+		guard.release()
+	}
+
+	/// Write data to flash while already holding the bus
+	pub fn write_locked(
+		&self,
+		guard: &BusGuard,
+		offset: usize,
+		source: &[u8],
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		guard.check(self);
+		track(pbf.create(source.len(), "Writing", None), |pb| {
+			self.write_chunked(offset, source, false, pb)
+		})
+	}
+
+	/// Write data to flash, then check that the device still reports a sane state
+	///
+	/// This only confirms the device is still responsive and still grants us the bus.
+	/// No data is read back, so it's much weaker than [`QoobDevice::write_verified`].
+	pub fn write_checked(&self, offset: usize, source: &[u8], pbf: &impl PBF) -> QoobResult<()> {
+		track(pbf.create(source.len(), "Writing", None), |pb| {
+			let guard = self.lock()?;
+			self.write_chunked(offset, source, false, pb)?;
+			if !self.status_info()?.bus_held() {
+				return Err(QoobError::VerificationError);
+			}
+			guard.release()
+		})
+	}
+
+	/// Write data to flash, reading back each chunk as soon as it's written
+	///
+	/// Stops at the first chunk that doesn't match,
+	/// and only needs one chunk's worth of extra memory.
+	pub fn write_verified(&self, offset: usize, source: &[u8], pbf: &impl PBF) -> QoobResult<()> {
+		track(pbf.create(source.len(), "Writing", None), |pb| {
+			let guard = self.lock()?;
+			self.write_chunked(offset, source, true, pb)?;
+			guard.release()
+		})
+	}
+
+	/// Check that a blank sector can be erased, written and read back correctly
+	///
+	/// The sector is left blank afterwards.
+	/// Fails with [`QoobError::RangeOccupied`] without touching it if it isn't blank to begin with.
+	/// Failures of the individual steps are recorded in the report instead.
+	pub fn selftest(&self, sector: usize, pbf: &impl PBF) -> QoobResult<SelfTestReport> {
+		self.geometry
+			.check_sectors(sector..sector.saturating_add(1))?;
+		let offset = self.geometry.sector_to_offset(sector);
+		// Mix in the upper address bits so that address line faults show up
+		let pattern: Vec<u8> = (0..self.geometry.sector_size)
+			.map(|i| (i ^ (i >> 8) ^ (offset >> 16)) as u8)
+			.collect();
+
+		track(pbf.create(4, "Testing", Some(" steps")), |pb| {
+			let guard = self.lock()?;
+			if !self.is_blank_locked(&guard, sector)? {
+				return Err(QoobError::RangeOccupied);
+			}
+
+			let attempt = |step| match step {
+				SelfTestStep::Erase => self.erase_raw(sector),
+				SelfTestStep::Write => self.write_chunked(offset, &pattern, false, &()),
+				SelfTestStep::Verify => {
+					let mut data = vec![0; self.geometry.sector_size];
+					self.read_chunked(offset, &mut data, &())?;
+					match data.iter().zip(&pattern).position(|(a, e)| a != e) {
+						Some(i) => Err(QoobError::VerificationMismatch {
+							offset: offset + i,
+							expected: pattern[i],
+							found: data[i],
+						}),
+						None => Ok(()),
+					}
+				}
+				SelfTestStep::Cleanup => {
+					self.erase_raw(sector)?;
+					if !self.is_blank_locked(&guard, sector)? {
+						return Err(QoobError::VerificationError);
+					}
+					Ok(())
+				}
+			};
+
+			let mut steps = Vec::new();
+			for step in [
+				SelfTestStep::Erase,
+				SelfTestStep::Write,
+				SelfTestStep::Verify,
+			] {
+				let result = attempt(step);
+				let failed = result.is_err();
+				steps.push((step, result));
+				pb.inc(1);
+				if failed {
+					break;
+				}
+			}
+			// Leave the sector blank no matter what
+			steps.push((SelfTestStep::Cleanup, attempt(SelfTestStep::Cleanup)));
+			pb.inc(1);
+
+			guard.release()?;
+			Ok(SelfTestReport { steps })
+		})
+	}
 }
 
-/// How many sectors `size` would span
-pub fn size_to_sectors(size: usize) -> usize {
-	(size + SECTOR_SIZE - 1) / SECTOR_SIZE
+/// Shorthands for callers that don't display progress
+impl QoobDevice {
+	/// [`QoobDevice::read`] without progress reporting
+	pub fn read_silent(&self, offset: usize, dest: &mut [u8]) -> QoobResult<()> {
+		self.read(offset, dest, &())
+	}
+
+	/// [`QoobDevice::write`] without progress reporting
+	pub fn write_silent(&self, offset: usize, source: &[u8]) -> QoobResult<()> {
+		self.write(offset, source, &())
+	}
+
+	/// [`QoobDevice::erase`] without progress reporting
+	pub fn erase_silent(&self, sectors: std::ops::Range<usize>) -> QoobResult<()> {
+		self.erase(sectors, &())
+	}
+}
+
+/// Exclusive access to the flash bus, obtained with [`QoobDevice::lock`]
+///
+/// The bus is released when the guard is dropped.
+/// Use [`BusGuard::release`] to find out whether that succeeded.
+pub struct BusGuard<'a> {
+	dev: &'a QoobDevice,
+}
+
+impl BusGuard<'_> {
+	/// Release the bus, reporting any error
+	pub fn release(self) -> QoobResult<()> {
+		let dev = self.dev;
+		std::mem::forget(self);
+		dev.release_bus()
+	}
+
+	/// Make sure this guard was obtained from `dev`
+	fn check(&self, dev: &QoobDevice) {
+		assert!(
+			std::ptr::eq(self.dev, dev),
+			"bus guard belongs to another device"
+		);
+	}
+}
+
+impl Drop for BusGuard<'_> {
+	fn drop(&mut self) {
+		if let Err(error) = self.dev.release_bus() {
+			match &self.dev.drop_error_hook {
+				Some(hook) => hook(error),
+				None => eprintln!("Failed to release the bus: {error}"),
+			}
+		}
+	}
 }
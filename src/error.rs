@@ -8,18 +8,81 @@ use hidapi::HidError;
 pub enum QoobError {
 	NoDev,
 	MultipleDevs,
+	Disconnected,
+	/// A report was cut short during `operation`
 	PartialTransfer {
+		operation: &'static str,
 		transferred: usize,
 		requested: usize,
 	},
 	BusBusy,
+	/// The device took too long to complete an operation
+	Timeout {
+		operation: &'static str,
+	},
+	UnsupportedGeometry,
+	/// A write failed partway through
+	///
+	/// Everything before `offset` was transferred successfully.
+	WriteFailed {
+		offset: usize,
+		written: usize,
+		error: Box<QoobError>,
+	},
+	/// The operation was cancelled before reaching `offset`
+	Cancelled {
+		offset: usize,
+	},
 	HidError(HidError),
+	Io(std::io::Error),
 
 	NoSuchFile(usize),
+	/// The requested range of bytes lies outside flash
+	OutOfBounds {
+		offset: usize,
+		len: usize,
+	},
 	RangeOccupied,
+	/// A patch reaches past the file or blank area it starts in
+	PatchSpansFiles,
+	/// Two files can't trade places because the result would overlap
+	SwapOverlap,
+	/// A file can't be copied onto itself
+	SameSlot,
+	/// No run of free sectors is big enough
+	NoSpace,
 	TooBig,
 	InvalidHeader,
+	/// The name doesn't match any [`FileType`](crate::fs::FileType)
+	UnknownFileType(String),
+	/// The size in a file's header doesn't match the length of the file
+	SizeMismatch {
+		declared: usize,
+		actual: usize,
+	},
+	InvalidDescription,
+	/// Data read back after writing doesn't match, without details of where
 	VerificationError,
+	/// Data read back after writing doesn't match, starting at `offset`
+	VerificationMismatch {
+		offset: usize,
+		expected: u8,
+		found: u8,
+	},
+	/// A manifest couldn't be parsed, starting at `line`
+	InvalidManifest {
+		line: usize,
+	},
+	/// The file for `slot` in a manifest can't be written
+	ManifestSlot {
+		slot: usize,
+		error: Box<QoobError>,
+	},
+	/// A flash image isn't exactly the size of flash
+	ImageSize {
+		expected: usize,
+		actual: usize,
+	},
 }
 
 impl fmt::Display for QoobError {
@@ -27,23 +90,115 @@ impl fmt::Display for QoobError {
 		match self {
 			Self::NoDev => write!(f, "Device not found"),
 			Self::MultipleDevs => write!(f, "Multiple devices are connected, can't choose one"),
+			Self::Disconnected => write!(f, "The device was disconnected"),
 			Self::PartialTransfer {
+				operation,
 				transferred,
 				requested,
 			} => {
 				write!(
 					f,
-					"Partial transfer: {transferred} out of {requested} bytes transferred",
+					"Partial transfer during {operation}: \
+					{transferred} out of {requested} bytes transferred",
 				)
 			}
 			Self::BusBusy => write!(f, "Bus busy, try again later"),
+			Self::Timeout { operation } => write!(f, "Timed out waiting for {operation}"),
+			Self::UnsupportedGeometry => write!(f, "The flash chip is not the expected size"),
+			Self::WriteFailed {
+				offset,
+				written,
+				error,
+			} => {
+				write!(
+					f,
+					"Write failed after {} KiB at {offset:#x}: {error}",
+					written / 1024,
+				)
+			}
+			Self::Cancelled { offset } => write!(f, "Cancelled at {offset:#x}"),
 			Self::HidError(e) => write!(f, "{e}"),
+			Self::Io(e) => write!(f, "{e}"),
 
 			Self::NoSuchFile(slot) => write!(f, "No file in slot {slot}"),
+			Self::OutOfBounds { offset, len } => {
+				write!(f, "{len} bytes at {offset:#x} are out of bounds")
+			}
 			Self::RangeOccupied => write!(f, "The destination range is not blank"),
+			Self::PatchSpansFiles => write!(f, "The patch extends past the file it starts in",),
+			Self::SwapOverlap => write!(
+				f,
+				"The files differ in size too much to trade places without overlapping",
+			),
+			Self::SameSlot => write!(f, "The source and destination are the same slot"),
+			Self::NoSpace => write!(f, "Not enough contiguous free space"),
 			Self::TooBig => write!(f, "The file is too big for the destination slot"),
 			Self::InvalidHeader => write!(f, "The file header is invalid"),
+			Self::UnknownFileType(name) => write!(f, "Unknown file type \"{name}\""),
+			Self::SizeMismatch { declared, actual } => write!(
+				f,
+				"The header declares {declared} bytes but the file is {actual} bytes long, \
+				it may be truncated",
+			),
+			Self::InvalidDescription => write!(
+				f,
+				"Descriptions must be at most 244 bytes long and can't contain NUL bytes",
+			),
 			Self::VerificationError => write!(f, "Data verification failed"),
+			Self::VerificationMismatch {
+				offset,
+				expected,
+				found,
+			} => write!(
+				f,
+				"Data verification failed: \
+				byte {offset:#x} was {found:#04x}, expected {expected:#04x}",
+			),
+			Self::InvalidManifest { line } => write!(f, "Invalid manifest at line {line}"),
+			Self::ManifestSlot { slot, error } => {
+				write!(f, "Manifest entry for slot {slot}: {error}")
+			}
+			Self::ImageSize { expected, actual } => write!(
+				f,
+				"Flash images must be {expected} bytes long, got {actual}",
+			),
+		}
+	}
+}
+
+impl QoobError {
+	/// The name of the error variant, for machine-readable output
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Self::NoDev => "NoDev",
+			Self::MultipleDevs => "MultipleDevs",
+			Self::Disconnected => "Disconnected",
+			Self::PartialTransfer { .. } => "PartialTransfer",
+			Self::BusBusy => "BusBusy",
+			Self::Timeout { .. } => "Timeout",
+			Self::UnsupportedGeometry => "UnsupportedGeometry",
+			Self::WriteFailed { .. } => "WriteFailed",
+			Self::Cancelled { .. } => "Cancelled",
+			Self::HidError(_) => "HidError",
+			Self::Io(_) => "Io",
+
+			Self::NoSuchFile(_) => "NoSuchFile",
+			Self::OutOfBounds { .. } => "OutOfBounds",
+			Self::RangeOccupied => "RangeOccupied",
+			Self::PatchSpansFiles => "PatchSpansFiles",
+			Self::SwapOverlap => "SwapOverlap",
+			Self::SameSlot => "SameSlot",
+			Self::NoSpace => "NoSpace",
+			Self::TooBig => "TooBig",
+			Self::InvalidHeader => "InvalidHeader",
+			Self::UnknownFileType(_) => "UnknownFileType",
+			Self::SizeMismatch { .. } => "SizeMismatch",
+			Self::InvalidDescription => "InvalidDescription",
+			Self::VerificationError => "VerificationError",
+			Self::VerificationMismatch { .. } => "VerificationMismatch",
+			Self::InvalidManifest { .. } => "InvalidManifest",
+			Self::ManifestSlot { .. } => "ManifestSlot",
+			Self::ImageSize { .. } => "ImageSize",
 		}
 	}
 }
@@ -54,6 +209,21 @@ impl From<HidError> for QoobError {
 	}
 }
 
-impl Error for QoobError {}
+impl From<std::io::Error> for QoobError {
+	fn from(error: std::io::Error) -> Self {
+		Self::Io(error)
+	}
+}
+
+impl Error for QoobError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			Self::WriteFailed { error, .. } | Self::ManifestSlot { error, .. } => Some(error),
+			Self::HidError(e) => Some(e),
+			Self::Io(e) => Some(e),
+			_ => None,
+		}
+	}
+}
 
 pub type QoobResult<T> = Result<T, QoobError>;
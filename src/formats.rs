@@ -0,0 +1,4 @@
+//! Helpers for the contents of specific file types
+
+pub mod bios;
+pub mod config;
@@ -0,0 +1,34 @@
+//! BIOS image classification
+
+/// Broad classification of a BIOS image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BiosKind {
+	/// The stock Qoob BIOS
+	Qoob,
+	/// Any other BIOS (Cobra, iGR, gekkoboot, ...)
+	Other,
+}
+
+impl BiosKind {
+	pub fn str(&self) -> &'static str {
+		match self {
+			Self::Qoob => "stock Qoob",
+			Self::Other => "custom",
+		}
+	}
+}
+
+/// Magics of the files the stock Qoob BIOS looks up in flash
+///
+/// Other BIOSes have no reason to reference all of them.
+const QOOB_SIGNATURES: [&[u8; 4]; 3] = [b"QPIC", b"QCFG", b"QCHT"];
+
+/// Classify a BIOS image by the signatures it contains
+pub fn classify(data: &[u8]) -> BiosKind {
+	let contains = |needle: &[u8]| data.windows(needle.len()).any(|w| w == needle);
+	if QOOB_SIGNATURES.iter().all(|&sig| contains(sig)) {
+		BiosKind::Qoob
+	} else {
+		BiosKind::Other
+	}
+}
@@ -0,0 +1,51 @@
+//! The stock Qoob BIOS configuration file
+//!
+//! The layout of the settings hasn't been documented,
+//! so this is only a container: it checks the header and keeps the file intact,
+//! but decodes none of the settings, such as the default boot slot or the video mode.
+//! They're exposed as raw bytes that survive a round trip untouched.
+
+use crate::fs::{FileType, HEADER_SIZE};
+use crate::{QoobError, QoobResult};
+
+/// A `QCFG` file, with its settings left as raw bytes
+///
+/// Editing them means knowing which byte holds what, which is up to the caller.
+#[derive(Clone, Debug)]
+pub struct QoobConfig {
+	/// The whole file, header included, truncated to the size in its header
+	data: Vec<u8>,
+}
+
+impl QoobConfig {
+	/// Parse a configuration file, such as one read from flash
+	///
+	/// Anything past the size stored in the header, like sector padding, is dropped.
+	pub fn parse(data: &[u8]) -> QoobResult<Self> {
+		if data.len() < HEADER_SIZE || data[0..4] != FileType::Config.magic() {
+			return Err(QoobError::InvalidHeader);
+		}
+		let size = u32::from_be_bytes(data[0xFC..=0xFF].try_into().unwrap()) as usize;
+		if size < HEADER_SIZE || size > data.len() {
+			return Err(QoobError::InvalidHeader);
+		}
+		Ok(Self {
+			data: data[..size].to_vec(),
+		})
+	}
+
+	/// The settings, everything after the header
+	pub fn settings(&self) -> &[u8] {
+		&self.data[HEADER_SIZE..]
+	}
+
+	/// The settings, for editing in place
+	pub fn settings_mut(&mut self) -> &mut [u8] {
+		&mut self.data[HEADER_SIZE..]
+	}
+
+	/// The file, ready to be written back to flash
+	pub fn to_bytes(&self) -> Vec<u8> {
+		self.data.clone()
+	}
+}
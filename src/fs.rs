@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::device;
-use crate::util::{ProgressBar, ProgressBarFactory as PBF};
+use crate::device::{BusGuard, Geometry};
+use crate::formats::bios::{self, BiosKind};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::util::{track, Crc32, ProgressBar, ProgressBarFactory as PBF};
 use crate::QoobDevice;
 use crate::{QoobError, QoobResult};
 
@@ -16,7 +18,7 @@ pub enum SectorOccupancy {
 	Slot(usize),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 /// Known file types
 pub enum FileType {
 	Bios,
@@ -59,6 +61,69 @@ impl FileType {
 		}
 	}
 
+	/// All known file types
+	pub fn all() -> &'static [FileType] {
+		&[
+			Self::Bios,
+			Self::Background,
+			Self::Config,
+			Self::CheatDb,
+			Self::CheatEngine,
+			Self::Bin,
+			Self::Dol,
+			Self::Elf,
+			Self::Swiss,
+		]
+	}
+
+	/// The magic identifying this type in a header
+	pub fn magic(&self) -> [u8; 4] {
+		match self {
+			Self::Bios => *b"(C) ",
+			Self::Background => *b"QPIC",
+			Self::Config => *b"QCFG",
+			Self::CheatDb => *b"QCHT",
+			Self::CheatEngine => *b"QCHE",
+			Self::Bin => *b"BIN\0",
+			Self::Dol => *b"DOL\0",
+			Self::Elf => *b"ELF\0",
+			Self::Swiss => *b"SWIS",
+			Self::Unknown(magic) => *magic,
+		}
+	}
+
+	/// Whether files of this type contain executable code
+	pub fn is_bootable(&self) -> bool {
+		matches!(self, Self::Bios | Self::Bin | Self::Dol | Self::Elf)
+	}
+
+	/// Guess the type of a bare payload, for wrapping it in a Qoob header
+	///
+	/// ELF files are recognized by their magic number.
+	/// DOL files have none, so `data` is taken to be a DOL if its 256-byte header is plausible:
+	/// the padding at the end is zeroed, the entry point and the load addresses of all
+	/// non-empty sections lie in main memory, those sections lie within `data`,
+	/// and there's at least one text section.
+	/// Anything else is considered to be arbitrary data and stored as a Swiss file.
+	///
+	/// Both ELF and DOL files are reported as [`FileType::Elf`],
+	/// as that's what the original Qoob BIOS boots either from.
+	pub fn detect(data: &[u8]) -> FileType {
+		if data.starts_with(b"\x7fELF") || is_dol(data) {
+			Self::Elf
+		} else {
+			Self::Swiss
+		}
+	}
+
+	/// Whether a bare payload of this type can be wrapped in a Qoob header
+	///
+	/// BIOS images carry their magic in their own first bytes,
+	/// so they can't be wrapped.
+	pub fn is_wrappable(&self) -> bool {
+		!matches!(self, Self::Bios | Self::Unknown(_))
+	}
+
 	pub fn str(&self) -> &'static str {
 		match self {
 			Self::Bios => "BIOS",
@@ -75,20 +140,226 @@ impl FileType {
 	}
 }
 
+impl std::fmt::Display for FileType {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.str())
+	}
+}
+
+impl std::str::FromStr for FileType {
+	type Err = QoobError;
+
+	/// Parse the name given by [`FileType::str`], ignoring case
+	fn from_str(s: &str) -> QoobResult<Self> {
+		Self::all()
+			.iter()
+			.find(|t| t.str().eq_ignore_ascii_case(s))
+			.copied()
+			.ok_or_else(|| QoobError::UnknownFileType(s.to_owned()))
+	}
+}
+
+/// Reads a slot from flash as it's consumed, see [`QoobFs::reader`]
+pub struct SlotReader<'a> {
+	dev: &'a QoobDevice,
+	_guard: BusGuard<'a>,
+	cursor: usize,
+	end: usize,
+}
+
+impl std::io::Read for SlotReader<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let len = buf
+			.len()
+			.min(self.dev.transfer_size())
+			.min(self.end - self.cursor);
+		if len == 0 {
+			return Ok(0);
+		}
+		self.dev
+			.read_raw(self.cursor, &mut buf[..len], &())
+			.map_err(std::io::Error::other)?;
+		self.cursor += len;
+		Ok(len)
+	}
+}
+
+/// Writes a file to a slot as it's produced, see [`QoobFs::writer`]
+pub struct SlotWriter<'a> {
+	fs: &'a mut QoobFs,
+	slot: usize,
+	/// Where the buffered data goes
+	cursor: usize,
+	end: usize,
+	buf: Vec<u8>,
+	/// The start of the file, to update the table of contents with
+	///
+	/// Once complete, it has been checked and its size padded.
+	header: Vec<u8>,
+	finished: bool,
+}
+
+impl SlotWriter<'_> {
+	/// Write out the buffered data
+	///
+	/// An incomplete header is kept buffered, as it can't be checked yet.
+	fn write_buf(&mut self) -> QoobResult<()> {
+		if !self.buf.is_empty() && self.header.len() == HEADER_SIZE {
+			self.fs.dev.write(self.cursor, &self.buf, &())?;
+			self.cursor += self.buf.len();
+			self.buf.clear();
+		}
+		Ok(())
+	}
+
+	/// Write out the remaining data and record the new file
+	///
+	/// Fails with [`QoobError::InvalidHeader`] if not even a whole header was written.
+	pub fn finish(mut self) -> QoobResult<()> {
+		self.finished = true;
+		if self.header.len() < HEADER_SIZE {
+			return Err(QoobError::InvalidHeader);
+		}
+		self.write_buf()?;
+		self.record();
+		Ok(())
+	}
+
+	/// Update the sector map with whatever made it to flash
+	fn record(&mut self) {
+		let geometry = self.fs.geometry();
+		let written = geometry.size_to_sectors(self.cursor - geometry.sector_to_offset(self.slot));
+		if written == 0 {
+			// The destination was erased and nothing else happened to it
+			return;
+		}
+		// Only a complete header that passed the checks is ever written out
+		self.fs
+			.record_header(self.slot, self.header[..].try_into().unwrap());
+		for sector in &mut self.fs.sector_map[self.slot..self.slot + written] {
+			if matches!(sector, SectorOccupancy::Empty) {
+				*sector = SectorOccupancy::Unknown;
+			}
+		}
+	}
+}
+
+impl std::io::Write for SlotWriter<'_> {
+	fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+		let len = data
+			.len()
+			.min(self.fs.geometry().sector_size - self.buf.len())
+			.min(self.end - self.cursor - self.buf.len());
+		if len == 0 && !data.is_empty() {
+			return Err(std::io::Error::other(QoobError::TooBig));
+		}
+		let data = &data[..len];
+		let header_len = data.len().min(HEADER_SIZE - self.header.len());
+		let completes_header = header_len > 0 && self.header.len() + header_len == HEADER_SIZE;
+		let mut header = [0; HEADER_SIZE];
+		if completes_header {
+			header[..self.header.len()].copy_from_slice(&self.header);
+			header[self.header.len()..].copy_from_slice(&data[..header_len]);
+		}
+		self.header.extend_from_slice(&data[..header_len]);
+		self.buf.extend_from_slice(data);
+		if completes_header {
+			let expected_len = self.end - self.fs.geometry().sector_to_offset(self.slot);
+			let Some(header) = check_header(header, expected_len, self.fs.geometry()) else {
+				self.header.truncate(self.header.len() - header_len);
+				self.buf.truncate(self.buf.len() - len);
+				return Err(std::io::Error::other(QoobError::InvalidHeader));
+			};
+			// Pad the size like QoobFs::write does. The header is still in the buffer
+			let size = padded_size(&header, self.fs.geometry());
+			self.header[0xFC..=0xFF].copy_from_slice(&size);
+			self.buf[0xFC..=0xFF].copy_from_slice(&size);
+		}
+		if self.buf.len() == self.fs.geometry().sector_size {
+			self.write_buf().map_err(std::io::Error::other)?;
+		}
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.write_buf().map_err(std::io::Error::other)
+	}
+}
+
+impl Drop for SlotWriter<'_> {
+	fn drop(&mut self) {
+		if !self.finished {
+			// Errors can't be reported from here, use finish() to get them
+			let _ = self.write_buf();
+			self.record();
+		}
+	}
+}
+
+/// Check whether `data` looks like a DOL executable, see [`FileType::detect`]
+fn is_dol(data: &[u8]) -> bool {
+	const MEM: std::ops::Range<u32> = 0x8000_0000..0x8180_0000;
+	const SECTIONS: usize = 18;
+	const TEXT_SECTIONS: usize = 7;
+
+	if data.len() < 0x100 || data[0xE4..0x100].iter().any(|&b| b != 0) {
+		return false;
+	}
+	let word = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+	if !MEM.contains(&word(0xE0)) {
+		return false;
+	}
+
+	let mut has_text = false;
+	for i in 0..SECTIONS {
+		let offset = word(4 * i) as usize;
+		let address = word(0x48 + 4 * i);
+		let size = word(0x90 + 4 * i) as usize;
+		if size == 0 {
+			continue;
+		}
+		let in_bounds = offset
+			.checked_add(size)
+			.is_some_and(|end| end <= data.len());
+		if offset < 0x100 || !in_bounds || !MEM.contains(&address) {
+			return false;
+		}
+		has_text |= i < TEXT_SECTIONS;
+	}
+	has_text
+}
+
 /// Size of a Qoob file header
 pub const HEADER_SIZE: usize = 256;
+/// Size of the description field in a Qoob file header
+pub const DESCRIPTION_SIZE: usize = 244;
 
 /// Newtype for Qoob file headers with accessors
 pub struct Header([u8; HEADER_SIZE]);
 
 impl Header {
+	/// Start building a header for a payload
+	pub fn builder() -> HeaderBuilder {
+		HeaderBuilder::default()
+	}
+
+	/// The header as stored on flash
+	pub fn as_bytes(&self) -> &[u8; HEADER_SIZE] {
+		&self.0
+	}
+
 	/// Returns the file type
 	pub fn r#type(&self) -> FileType {
-		FileType::from_magic(self.0[0..4].try_into().unwrap())
+		FileType::from_magic(&self.magic())
+	}
+
+	/// The raw magic number the file type is derived from
+	pub fn magic(&self) -> [u8; 4] {
+		self.0[0..4].try_into().unwrap()
 	}
 
 	/// The raw description field
-	pub fn description(&self) -> &[u8; 244] {
+	pub fn description(&self) -> &[u8; DESCRIPTION_SIZE] {
 		self.0[0x04..=0xF7].try_into().unwrap()
 	}
 
@@ -103,14 +374,27 @@ impl Header {
 		)
 	}
 
+	/// The description field decoded for display
+	///
+	/// Decoded as UTF-8 if valid, Latin-1 otherwise.
+	/// Unlike [`Header::description_string`], this can't be turned back into the exact bytes.
+	pub fn description_lossy(&self) -> String {
+		let len = self.description().iter().position(|&b| b == 0);
+		let bytes = &self.description()[..len.unwrap_or(DESCRIPTION_SIZE)];
+		match std::str::from_utf8(bytes) {
+			Ok(s) => s.to_owned(),
+			Err(_) => bytes.iter().map(|&b| b as char).collect(),
+		}
+	}
+
 	/// The size in bytes
 	pub fn size(&self) -> usize {
 		u32::from_be_bytes(self.0[0xFC..=0xFF].try_into().unwrap()) as usize
 	}
 
-	/// How many sectors the file spans
-	pub fn sector_count(&self) -> usize {
-		device::size_to_sectors(self.size())
+	/// How many sectors the file spans on a chip laid out like `geometry`
+	pub fn sector_count(&self, geometry: Geometry) -> usize {
+		geometry.size_to_sectors(self.size())
 	}
 }
 
@@ -120,12 +404,65 @@ impl std::fmt::Debug for Header {
 			.field("type", &self.r#type())
 			.field("description", &self.description_string())
 			.field("size", &self.size())
-			.field("sector_count", &self.sector_count())
 			.finish()
 	}
 }
 
+/// Builds a [`Header`] for a payload, see [`Header::builder`]
+#[derive(Debug, Default)]
+pub struct HeaderBuilder {
+	file_type: Option<FileType>,
+	description: String,
+	payload_len: usize,
+}
+
+impl HeaderBuilder {
+	/// Set the file type, which is required
+	pub fn file_type(mut self, file_type: FileType) -> Self {
+		self.file_type = Some(file_type);
+		self
+	}
+
+	/// Set the description, empty by default
+	pub fn description(mut self, description: &str) -> Self {
+		self.description = description.to_owned();
+		self
+	}
+
+	/// Set the length of the payload that follows the header
+	pub fn payload_len(mut self, len: usize) -> Self {
+		self.payload_len = len;
+		self
+	}
+
+	/// Assemble the header
+	///
+	/// The size field covers the header itself along with the payload.
+	/// Fails with [`QoobError::InvalidHeader`] if no file type was set
+	/// or it isn't [wrappable](FileType::is_wrappable),
+	/// [`QoobError::InvalidDescription`] if the description doesn't fit,
+	/// or [`QoobError::TooBig`] if the size doesn't fit in the header.
+	pub fn build(self) -> QoobResult<Header> {
+		let file_type = self
+			.file_type
+			.filter(FileType::is_wrappable)
+			.ok_or(QoobError::InvalidHeader)?;
+		let description = encode_description(&self.description)?;
+		let size = HEADER_SIZE
+			.checked_add(self.payload_len)
+			.filter(|&size| u32::try_from(size).is_ok())
+			.ok_or(QoobError::TooBig)?;
+
+		let mut header = [0; HEADER_SIZE];
+		header[0..4].copy_from_slice(&file_type.magic());
+		header[0x04..=0xF7].copy_from_slice(&description);
+		header[0xFC..=0xFF].copy_from_slice(&u32::to_be_bytes(size as _));
+		Ok(Header(header))
+	}
+}
+
 /// The result of a pre-write range check
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RangeCheck {
 	/// The destination range is blank
 	Empty,
@@ -137,22 +474,166 @@ pub enum RangeCheck {
 	Overflow,
 }
 
+/// How to check data after writing it
+#[derive(Clone, Copy, Debug, Default)]
+pub enum VerifyMode {
+	/// Don't check
+	#[default]
+	Off,
+	/// Read each chunk back as soon as it's written and compare it byte for byte
+	///
+	/// Stops at the first mismatch, so later sectors aren't written to needlessly.
+	Readback,
+	/// Read the data back and compare its CRC-32 to that of the source
+	///
+	/// Doesn't need a second copy of the data in memory,
+	/// at the cost of a tiny chance of missing corruption.
+	Checksum,
+	/// Don't read anything back, only check the device status after writing
+	///
+	/// Faster than the other modes, but only catches gross failures
+	/// such as the device losing the bus or becoming unresponsive.
+	StatusOnly,
+}
+
+/// Options for [`QoobFs::write`]
+#[derive(Clone, Copy, Debug)]
+pub struct WriteOptions {
+	/// How to check the data after writing it
+	pub verify: VerifyMode,
+	/// Round the size in the header up to a whole number of sectors, as the format specifies
+	///
+	/// When unset, the size is kept as is, so the exact length of the file can be recovered
+	/// with [`QoobFs::read_sized`].
+	pub pad_size: bool,
+	/// Refuse files whose header doesn't declare exactly their own length
+	///
+	/// By default, a declared size that rounds up to the same number of sectors is accepted,
+	/// which lets a truncated file through as long as it only lost part of its last sector.
+	pub strict_size: bool,
+	/// Erase whatever occupies the destination first, if it's a file starting in the same slot
+	/// or unrecognized data
+	///
+	/// Writing over another file that merely extends into the destination still fails.
+	pub overwrite: bool,
+}
+
+impl Default for WriteOptions {
+	fn default() -> Self {
+		Self {
+			verify: VerifyMode::default(),
+			pad_size: true,
+			strict_size: false,
+			overwrite: false,
+		}
+	}
+}
+
+/// A file relocation, as planned by [`QoobFs::defrag_plan`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Move {
+	/// The slot the file starts in now
+	pub from: usize,
+	/// The slot the file will start in
+	pub to: usize,
+	/// How many sectors the file spans
+	pub sectors: usize,
+}
+
+/// What a scan found, as returned by [`QoobFs::scan`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanReport {
+	/// How many valid files were found
+	pub files: usize,
+	/// Sectors that aren't blank but don't start with a valid header or belong to a file
+	///
+	/// These may hold a corrupted file, or data written by something else.
+	pub unknown_sectors: Vec<usize>,
+	/// How many sectors start with a blank header
+	pub empty_sectors: usize,
+	/// Headers found within the span of another file
+	pub conflicts: Vec<ScanConflict>,
+}
+
+/// A sector that differs from an image, as found by [`QoobFs::diff_image`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectorDiff {
+	pub sector: usize,
+	/// The offset in flash of the first byte that differs
+	pub first_difference: usize,
+}
+
+/// How flash space is used, as returned by [`QoobFs::usage`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Usage {
+	/// Sectors holding files
+	pub used_sectors: usize,
+	/// Sectors that start with a blank header
+	pub free_sectors: usize,
+	/// Sectors with unrecognized contents
+	pub unknown_sectors: usize,
+	/// Sectors held by files of each type present, in the order of [`FileType::all`]
+	pub by_type: Vec<(FileType, usize)>,
+}
+
+/// A file header found within the span of another file
+///
+/// Only the file that starts first is listed; the other one was presumably partly overwritten.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanConflict {
+	/// The sector holding the header
+	pub sector: usize,
+	/// The slot of the file whose span it lies in
+	pub slot: usize,
+}
+
+/// What writing a file would do, as planned by [`QoobFs::plan_write`]
+#[derive(Debug)]
+pub struct WritePlan {
+	/// The header of the file to write
+	pub header: Header,
+	/// The sectors the file would span
+	pub range: std::ops::Range<usize>,
+	/// What currently occupies those sectors
+	pub check: RangeCheck,
+	/// Whether the sectors hold a file that must be erased first
+	pub needs_erase: bool,
+}
+
+/// The result of comparing a file with the contents of a slot
+#[derive(Debug)]
+pub struct FileComparison {
+	/// Whether the file spans as many sectors as the one in the slot
+	pub size_matches: bool,
+	/// Offset of the first byte that differs, if any
+	pub first_difference: Option<usize>,
+}
+
+impl FileComparison {
+	/// Whether the contents are identical
+	pub fn content_matches(&self) -> bool {
+		self.first_difference.is_none()
+	}
+}
+
 /// A wrapper for [`QoobDevice`] that's aware of the "filesystem"
 ///
 /// This API uses sectors as the addressing unit
 pub struct QoobFs {
 	dev: QoobDevice,
-	sector_map: [SectorOccupancy; device::SECTOR_COUNT],
-	toc: HashMap<usize, Header>,
+	sector_map: Vec<SectorOccupancy>,
+	toc: BTreeMap<usize, Header>,
+	conflicts: Vec<ScanConflict>,
 }
 
 impl QoobFs {
 	/// Initialize the filesystem wrapper
 	pub fn from_device(dev: QoobDevice, pbf: &impl PBF) -> QoobResult<Self> {
 		let mut fs = Self {
+			sector_map: vec![SectorOccupancy::Unknown; dev.geometry().sector_count],
 			dev,
-			sector_map: [SectorOccupancy::Unknown; device::SECTOR_COUNT],
-			toc: HashMap::new(),
+			toc: BTreeMap::new(),
+			conflicts: Vec::new(),
 		};
 
 		fs.scan(pbf)?;
@@ -160,162 +641,1912 @@ impl QoobFs {
 		Ok(fs)
 	}
 
+	/// The layout of the flash chip
+	pub fn geometry(&self) -> Geometry {
+		self.dev.geometry()
+	}
+
 	fn inspect_sector(&mut self, sector: usize) -> QoobResult<()> {
+		let header = self.read_header(sector)?;
+		self.record_header(sector, header);
+		Ok(())
+	}
+
+	fn read_header(&self, sector: usize) -> QoobResult<[u8; HEADER_SIZE]> {
 		let mut header = [0; HEADER_SIZE];
 		self.dev
-			.read_raw(sector * device::SECTOR_SIZE, &mut header, &())?;
+			.read_raw(self.geometry().sector_to_offset(sector), &mut header, &())?;
+		Ok(header)
+	}
 
+	/// Update the sector map and table of contents with the header found at `sector`
+	fn record_header(&mut self, sector: usize, header: [u8; HEADER_SIZE]) {
 		if header == [0xFF; HEADER_SIZE] {
 			self.sector_map[sector] = SectorOccupancy::Empty;
-		} else {
-			let file = Header(header);
-			if !matches!(file.r#type(), FileType::Unknown(_))
-				&& file.size() >= HEADER_SIZE
-				&& file.sector_count() < device::SECTOR_COUNT - sector
-			{
-				for i in sector..sector + file.sector_count() {
-					self.sector_map[i] = SectorOccupancy::Slot(sector);
-				}
-				self.toc.insert(sector, file);
-			} else {
-				self.sector_map[sector] = SectorOccupancy::Unknown;
+		} else if let Some(file) = plausible_header(sector, header, self.geometry()) {
+			for i in sector..sector + file.sector_count(self.geometry()) {
+				self.sector_map[i] = SectorOccupancy::Slot(sector);
 			}
+			self.toc.insert(sector, file);
+		} else {
+			self.sector_map[sector] = SectorOccupancy::Unknown;
 		}
-		Ok(())
 	}
 
-	/// Trigger a rescan of slot headers
-	pub fn scan(&mut self, pbf: &impl PBF) -> QoobResult<()> {
-		let pb = pbf.create(device::SECTOR_COUNT, "Scanning", Some(" sectors"));
-		self.toc.clear();
-		self.dev.get_bus()?;
+	/// Trigger a rescan of slot headers, summarizing what was found
+	pub fn scan(&mut self, pbf: &impl PBF) -> QoobResult<ScanReport> {
+		let pb = pbf.create(self.geometry().sector_count, "Scanning", Some(" sectors"));
+		track(pb, |pb| {
+			self.toc.clear();
+			self.conflicts.clear();
+			self.dev.get_bus()?;
+			// Give the bus back even if the scan fails, or later guards would never release it
+			let result = self.scan_sectors(pb);
+			let released = self.dev.release_bus();
+			result.and(released)
+		})?;
+		Ok(self.scan_report())
+	}
+
+	/// Inspect every sector, skipping over files.
+	///
+	/// The bus must be held.
+	fn scan_sectors(&mut self, pb: &impl ProgressBar) -> QoobResult<()> {
 		let mut cursor = 0;
-		while cursor < device::SECTOR_COUNT {
+		while cursor < self.geometry().sector_count {
 			self.inspect_sector(cursor)?;
-			cursor += match self.sector_map[cursor] {
-				SectorOccupancy::Slot(n) => self.toc[&n].sector_count(),
-				_ => 1,
+			let next = match self.sector_map[cursor] {
+				SectorOccupancy::Slot(n) => n + self.toc[&n].sector_count(self.geometry()),
+				_ => cursor + 1,
 			};
+			// A header within another file is a sign of a botched write
+			for sector in cursor + 1..next {
+				let header = self.read_header(sector)?;
+				if plausible_header(sector, header, self.geometry()).is_some() {
+					self.conflicts.push(ScanConflict {
+						sector,
+						slot: cursor,
+					});
+				}
+			}
+			cursor = next;
 			pb.set(cursor);
 		}
-		self.dev.release_bus()?;
-		pb.finish();
 		Ok(())
 	}
 
+	/// Summarize the sector map built by the last scan
+	pub fn scan_report(&self) -> ScanReport {
+		let mut report = ScanReport {
+			files: self.toc.len(),
+			unknown_sectors: Vec::new(),
+			empty_sectors: 0,
+			conflicts: self.conflicts.clone(),
+		};
+		for (i, sector) in self.sector_map.iter().enumerate() {
+			match sector {
+				SectorOccupancy::Empty => report.empty_sectors += 1,
+				SectorOccupancy::Unknown => report.unknown_sectors.push(i),
+				SectorOccupancy::Slot(_) => {}
+			}
+		}
+		report
+	}
+
 	/// Iterate over sectors, returning their occupancy status
 	pub fn iter_slots(&self) -> impl Iterator<Item = &SectorOccupancy> {
 		self.sector_map.iter()
 	}
 
+	/// Iterate over files in ascending slot order, returning their slot and header
+	pub fn iter_files(&self) -> impl Iterator<Item = (usize, &Header)> {
+		self.toc.iter().map(|(&slot, header)| (slot, header))
+	}
+
+	/// All file headers, keyed by slot
+	pub fn files(&self) -> &BTreeMap<usize, Header> {
+		&self.toc
+	}
+
 	/// Get the header for a slot
 	pub fn slot_info(&self, slot: usize) -> QoobResult<&Header> {
 		self.toc.get(&slot).ok_or(QoobError::NoSuchFile(slot))
 	}
 
+	/// How many consecutive sectors the sector map attributes to a slot
+	///
+	/// This should match [`Header::sector_count`];
+	/// a disagreement points to a corrupt map or header.
+	pub fn occupied_sectors(&self, slot: usize) -> Option<usize> {
+		self.toc.get(&slot)?;
+		let run = self.sector_map[slot..]
+			.iter()
+			.take_while(|s| matches!(s, SectorOccupancy::Slot(n) if *n == slot))
+			.count();
+		Some(run)
+	}
+
+	/// Read the header at the start of a sector, without validating it
+	///
+	/// Meant for inspecting [`SectorOccupancy::Unknown`] sectors.
+	pub fn raw_header(&self, sector: usize) -> QoobResult<Header> {
+		if sector >= self.geometry().sector_count {
+			return Err(QoobError::OutOfBounds {
+				offset: sector.saturating_mul(self.geometry().sector_size),
+				len: HEADER_SIZE,
+			});
+		}
+		let offset = self.geometry().sector_to_offset(sector);
+		let mut header = [0; HEADER_SIZE];
+		self.dev.read(offset, &mut header, &())?;
+		Ok(Header(header))
+	}
+
 	/// Read a file
 	pub fn read(&self, slot: usize, pbf: &impl PBF) -> QoobResult<Vec<u8>> {
-		let info = self.slot_info(slot)?;
-		let mut data = vec![0; info.sector_count() * device::SECTOR_SIZE];
-		self.dev
-			.read(slot * device::SECTOR_SIZE, data.as_mut_slice(), pbf)?;
+		let mut data = Vec::new();
+		self.read_into(slot, &mut data, pbf)?;
 		Ok(data)
 	}
 
-	/// Erase a file
-	pub fn remove(&mut self, slot: usize, pbf: &impl PBF) -> QoobResult<()> {
+	/// Read a file into an existing buffer, resizing it to fit
+	///
+	/// Reusing the buffer saves allocations when reading many files.
+	pub fn read_into(&self, slot: usize, buf: &mut Vec<u8>, pbf: &impl PBF) -> QoobResult<()> {
 		let info = self.slot_info(slot)?;
-		let range = slot..slot + info.sector_count();
-		self.dev.erase(range.clone(), pbf)?;
-
-		for i in range {
-			self.sector_map[i] = SectorOccupancy::Empty;
-		}
-		self.toc.remove(&slot);
-
-		Ok(())
+		buf.resize(
+			self.geometry()
+				.sector_to_offset(info.sector_count(self.geometry())),
+			0,
+		);
+		self.dev
+			.read(self.geometry().sector_to_offset(slot), buf, pbf)
 	}
 
-	/// Check whether it's possible to write to a given range
-	pub fn check_dest_range(&self, range: std::ops::Range<usize>) -> RangeCheck {
-		if range.end >= device::SECTOR_COUNT {
-			return RangeCheck::Overflow;
-		}
+	/// Read a file, truncated to the size stored in its header
+	///
+	/// Only differs from [`QoobFs::read`] for files written without
+	/// [`WriteOptions::pad_size`].
+	pub fn read_sized(&self, slot: usize, pbf: &impl PBF) -> QoobResult<Vec<u8>> {
+		let size = self.slot_info(slot)?.size();
+		let mut data = self.read(slot, pbf)?;
+		data.truncate(size);
+		Ok(data)
+	}
 
-		let mut status = RangeCheck::Empty;
-		for i in range.clone() {
-			match self.sector_map[i] {
-				SectorOccupancy::Empty => {}
-				SectorOccupancy::Unknown => {
-					status = RangeCheck::Occupied;
-				}
-				SectorOccupancy::Slot(i) if i == range.start => {
-					status = RangeCheck::Occupied;
-				}
-				SectorOccupancy::Slot(_) => return RangeCheck::Overlap,
-			}
-		}
-		status
+	/// Read the contents of a file, without its header
+	///
+	/// The result is truncated to the size stored in the header,
+	/// which for files written with [`WriteOptions::pad_size`] still includes the padding.
+	///
+	/// Files that aren't [wrappable](FileType::is_wrappable), i.e. BIOS images,
+	/// are returned whole, as their header is part of the image.
+	pub fn read_file(&self, slot: usize, pbf: &impl PBF) -> QoobResult<Vec<u8>> {
+		let info = self.slot_info(slot)?;
+		let skip = if info.r#type().is_wrappable() {
+			HEADER_SIZE
+		} else {
+			0
+		};
+		let mut data = vec![0; info.size() - skip];
+		self.dev.read(
+			self.geometry().sector_to_offset(slot) + skip,
+			&mut data,
+			pbf,
+		)?;
+		Ok(data)
 	}
 
-	/// Write a new file, optionally verifying the written data
-	pub fn write(
-		&mut self,
+	/// Read `len` bytes starting `offset` bytes into a file's slot
+	///
+	/// The range must lie within the sectors the file spans.
+	pub fn read_range(
+		&self,
 		slot: usize,
-		data: &[u8],
-		verify: bool,
+		offset: usize,
+		len: usize,
 		pbf: &impl PBF,
-	) -> QoobResult<()> {
-		let header = validate_header(data).ok_or(QoobError::InvalidHeader)?;
+	) -> QoobResult<Vec<u8>> {
+		let info = self.slot_info(slot)?;
+		let start = self.geometry().sector_to_offset(slot);
+		let span = self
+			.geometry()
+			.sector_to_offset(info.sector_count(self.geometry()));
+		if offset.checked_add(len).is_none_or(|end| end > span) {
+			return Err(QoobError::OutOfBounds {
+				offset: start.saturating_add(offset),
+				len,
+			});
+		}
+		let mut data = vec![0; len];
+		self.dev.read(start + offset, &mut data, pbf)?;
+		Ok(data)
+	}
 
-		let dest_range = slot..slot + header.sector_count();
-		match self.check_dest_range(dest_range.clone()) {
+	/// Stream a file's slot, as returned by [`QoobFs::read`]
+	///
+	/// The bus is held until the reader is dropped,
+	/// even if other operations acquire and release it in the meantime.
+	pub fn reader(&self, slot: usize) -> QoobResult<SlotReader<'_>> {
+		let info = self.slot_info(slot)?;
+		let cursor = self.geometry().sector_to_offset(slot);
+		let end = cursor
+			+ self
+				.geometry()
+				.sector_to_offset(info.sector_count(self.geometry()));
+		Ok(SlotReader {
+			dev: &self.dev,
+			_guard: self.dev.lock()?,
+			cursor,
+			end,
+		})
+	}
+
+	/// Stream a new file into a slot
+	///
+	/// The `expected_len` bytes starting at `slot` must be free, and are erased up front.
+	/// Data is written to flash a sector at a time.
+	/// The header is checked against `expected_len` as soon as it's complete,
+	/// failing the write with [`QoobError::InvalidHeader`] before anything reaches flash,
+	/// and its size is padded as with [`WriteOptions::pad_size`].
+	/// Use [`SlotWriter::finish`] to find out whether the last of it was written successfully.
+	pub fn writer(&mut self, slot: usize, expected_len: usize) -> QoobResult<SlotWriter<'_>> {
+		if expected_len < HEADER_SIZE {
+			return Err(QoobError::InvalidHeader);
+		}
+		let range = self.file_range(slot, expected_len)?;
+		match self.check_dest_range(range.clone()) {
 			RangeCheck::Empty => Ok(()),
 			RangeCheck::Overflow => Err(QoobError::TooBig),
 			RangeCheck::Occupied | RangeCheck::Overlap => Err(QoobError::RangeOccupied),
 		}?;
+		self.dev.erase(range, &())?;
+		let cursor = self.geometry().sector_to_offset(slot);
+		let buf = Vec::with_capacity(self.geometry().sector_size);
+		Ok(SlotWriter {
+			fs: self,
+			slot,
+			cursor,
+			end: cursor + expected_len,
+			buf,
+			header: Vec::with_capacity(HEADER_SIZE),
+			finished: false,
+		})
+	}
 
-		let mut data = data.to_vec();
-		// The size is specified to be a multiple of 64KiB
-		let new_size = u32::to_be_bytes((header.sector_count() * device::SECTOR_SIZE) as _);
-		data[0xFC..=0xFF].copy_from_slice(&new_size);
+	/// Read `count` sectors starting at `slot`, regardless of what the header says
+	///
+	/// Meant for recovering files with a damaged size field,
+	/// e.g. using [`QoobFs::occupied_sectors`] as the count.
+	pub fn read_sectors_of(
+		&self,
+		slot: usize,
+		count: usize,
+		pbf: &impl PBF,
+	) -> QoobResult<Vec<u8>> {
+		let geometry = self.geometry();
+		if slot
+			.checked_add(count)
+			.is_none_or(|end| end > geometry.sector_count)
+		{
+			return Err(QoobError::OutOfBounds {
+				offset: slot.saturating_mul(geometry.sector_size),
+				len: count.saturating_mul(geometry.sector_size),
+			});
+		}
+		let offset = geometry.sector_to_offset(slot);
+		let len = geometry.sector_to_offset(count);
+		let mut data = vec![0; len];
+		self.dev.read(offset, &mut data, pbf)?;
+		Ok(data)
+	}
 
-		self.dev.write(slot * device::SECTOR_SIZE, &data, pbf)?;
+	/// Read every file, keyed by slot
+	pub fn read_all(&self, pbf: &impl PBF) -> QoobResult<HashMap<usize, Vec<u8>>> {
+		let total = self
+			.toc
+			.values()
+			.map(|info| {
+				self.geometry()
+					.sector_to_offset(info.sector_count(self.geometry()))
+			})
+			.sum();
+		track(pbf.create(total, "Reading", None), |pb| {
+			let guard = self.dev.lock()?;
+			let mut files = HashMap::new();
+			for (&slot, info) in &self.toc {
+				let mut data = vec![
+					0;
+					self.geometry()
+						.sector_to_offset(info.sector_count(self.geometry()))
+				];
+				self.dev
+					.read_chunked(self.geometry().sector_to_offset(slot), &mut data, pb)?;
+				files.insert(slot, data);
+			}
+			guard.release()?;
+			Ok(files)
+		})
+	}
 
-		if verify {
-			let mut verif_data = vec![0; data.len()];
-			self.dev
-				.read(slot * device::SECTOR_SIZE, &mut verif_data, pbf)?;
-			if verif_data != data {
-				return Err(QoobError::VerificationError);
+	/// Runs of sectors that aren't known to be empty
+	fn nonempty_runs(&self) -> Vec<std::ops::Range<usize>> {
+		runs(
+			self.sector_map
+				.iter()
+				.map(|s| !matches!(s, SectorOccupancy::Empty)),
+		)
+	}
+
+	/// Count sectors by what they hold
+	pub fn usage(&self) -> Usage {
+		let mut usage = Usage {
+			used_sectors: 0,
+			free_sectors: 0,
+			unknown_sectors: 0,
+			by_type: Vec::new(),
+		};
+		for sector in &self.sector_map {
+			match sector {
+				SectorOccupancy::Empty => usage.free_sectors += 1,
+				SectorOccupancy::Unknown => usage.unknown_sectors += 1,
+				SectorOccupancy::Slot(_) => usage.used_sectors += 1,
 			}
 		}
-
-		for i in dest_range {
-			self.sector_map[i] = SectorOccupancy::Slot(slot);
+		for &file_type in FileType::all() {
+			let sectors: usize = self
+				.toc
+				.values()
+				.filter(|header| header.r#type() == file_type)
+				.map(|file| file.sector_count(self.geometry()))
+				.sum();
+			if sectors != 0 {
+				usage.by_type.push((file_type, sectors));
+			}
 		}
-		let header = Header(data[0..HEADER_SIZE].try_into().unwrap());
-		self.toc.insert(slot, header);
+		usage
+	}
 
-		Ok(())
+	/// Every maximal run of empty sectors
+	pub fn free_runs(&self) -> Vec<std::ops::Range<usize>> {
+		runs(
+			self.sector_map
+				.iter()
+				.map(|s| matches!(s, SectorOccupancy::Empty)),
+		)
 	}
 
-	/// Retrieve the underlying device handle
-	pub fn into_device(self) -> QoobDevice {
-		self.dev
+	/// The longest run of empty sectors, the first one if there's a tie
+	pub fn largest_free_run(&self) -> Option<std::ops::Range<usize>> {
+		self.free_runs()
+			.into_iter()
+			.rev()
+			.max_by_key(|run| run.len())
 	}
+
+	/// How many sectors are empty in total
+	pub fn total_free_sectors(&self) -> usize {
+		self.sector_map
+			.iter()
+			.filter(|s| matches!(s, SectorOccupancy::Empty))
+			.count()
+	}
+
+	/// Read every run of sectors that isn't empty, keyed by starting sector
+	///
+	/// Sectors are considered empty based on the last scan, which only looks at their headers.
+	pub fn dump_sparse(&self, pbf: &impl PBF) -> QoobResult<Vec<(usize, Vec<u8>)>> {
+		let runs = self.nonempty_runs();
+		let total = runs
+			.iter()
+			.map(|run| self.geometry().sector_to_offset(run.len()))
+			.sum();
+		track(pbf.create(total, "Reading", None), |pb| {
+			let guard = self.dev.lock()?;
+			let mut dump = Vec::new();
+			for run in runs {
+				let mut data = vec![0; self.geometry().sector_to_offset(run.len())];
+				self.dev.read_chunked(
+					self.geometry().sector_to_offset(run.start),
+					&mut data,
+					pb,
+				)?;
+				dump.push((run.start, data));
+			}
+			guard.release()?;
+			Ok(dump)
+		})
+	}
+
+	/// Read an image of the whole flash, skipping sectors that are empty
+	///
+	/// Empty sectors are filled in with `0xFF`, as described in [`QoobFs::dump_sparse`].
+	pub fn dump_image(&self, pbf: &impl PBF) -> QoobResult<Vec<u8>> {
+		let mut image = vec![0xFF; self.geometry().flash_size()];
+		for (sector, data) in self.dump_sparse(pbf)? {
+			let offset = self.geometry().sector_to_offset(sector);
+			image[offset..offset + data.len()].copy_from_slice(&data);
+		}
+		Ok(image)
+	}
+
+	/// Restore an image of the whole flash, only rewriting sectors that differ
+	///
+	/// Each sector is read back and compared with the image first.
+	/// Sectors that already match are left alone,
+	/// and sectors that are already blank aren't erased before being written.
+	pub fn restore_image(&mut self, image: &[u8], pbf: &impl PBF) -> QoobResult<()> {
+		if image.len() != self.geometry().flash_size() {
+			return Err(QoobError::ImageSize {
+				expected: self.geometry().flash_size(),
+				actual: image.len(),
+			});
+		}
+		let pb = pbf.create(self.geometry().sector_count, "Restoring", Some(" sectors"));
+		track(pb, |pb| {
+			let guard = self.dev.lock()?;
+			let mut current = vec![0; self.geometry().sector_size];
+			for (sector, target) in image.chunks(self.geometry().sector_size).enumerate() {
+				let offset = self.geometry().sector_to_offset(sector);
+				self.dev.read_chunked(offset, &mut current, &())?;
+				if current != target {
+					if current.iter().any(|&b| b != 0xFF) {
+						self.dev.erase_locked(&guard, sector..sector + 1, &())?;
+					}
+					if target.iter().any(|&b| b != 0xFF) {
+						self.dev.write_locked(&guard, offset, target, &())?;
+					}
+				}
+				pb.inc(1);
+			}
+			guard.release()
+		})?;
+		self.scan(&())?;
+		Ok(())
+	}
+
+	/// Overwrite bytes anywhere in flash, preserving the rest of the sectors they fall in
+	///
+	/// The affected sectors are read, patched in memory, erased and written back.
+	/// Fails with [`QoobError::PatchSpansFiles`] if the patch reaches past the file
+	/// or blank area it starts in, unless `force` is set.
+	pub fn patch(
+		&mut self,
+		offset: usize,
+		data: &[u8],
+		force: bool,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		if offset
+			.checked_add(data.len())
+			.is_none_or(|end| end > self.geometry().flash_size())
+		{
+			return Err(QoobError::OutOfBounds {
+				offset,
+				len: data.len(),
+			});
+		}
+		if data.is_empty() {
+			return Ok(());
+		}
+		let first = self.geometry().offset_to_sector(offset);
+		let sectors = first..self.geometry().size_to_sectors(offset + data.len());
+		let owner = |i: usize| match self.sector_map[i] {
+			SectorOccupancy::Slot(n) => Some(n),
+			SectorOccupancy::Empty | SectorOccupancy::Unknown => None,
+		};
+		if !force && sectors.clone().any(|i| owner(i) != owner(first)) {
+			return Err(QoobError::PatchSpansFiles);
+		}
+
+		let pb = pbf.create(sectors.len(), "Patching", Some(" sectors"));
+		track(pb, |pb| {
+			let guard = self.dev.lock()?;
+			let start = self.geometry().sector_to_offset(sectors.start);
+			let mut current = vec![0; self.geometry().sector_to_offset(sectors.len())];
+			self.dev.read_chunked(start, &mut current, &())?;
+			let mut target = current.clone();
+			target[offset - start..][..data.len()].copy_from_slice(data);
+
+			let chunks = current.chunks(self.geometry().sector_size);
+			for ((sector, current), target) in sectors
+				.zip(chunks)
+				.zip(target.chunks(self.geometry().sector_size))
+			{
+				if current != target {
+					if current.iter().any(|&b| b != 0xFF) {
+						self.dev.erase_locked(&guard, sector..sector + 1, &())?;
+					}
+					let offset = self.geometry().sector_to_offset(sector);
+					self.dev.write_locked(&guard, offset, target, &())?;
+				}
+				pb.inc(1);
+			}
+			guard.release()
+		})?;
+		// Headers may have changed
+		self.scan(&())?;
+		Ok(())
+	}
+
+	/// Read a BIOS file and classify it
+	///
+	/// Returns `None` if the slot contains something other than a BIOS.
+	pub fn bios_kind(&self, slot: usize, pbf: &impl PBF) -> QoobResult<Option<BiosKind>> {
+		if !matches!(self.slot_info(slot)?.r#type(), FileType::Bios) {
+			return Ok(None);
+		}
+		let data = self.read(slot, pbf)?;
+		Ok(Some(bios::classify(&data)))
+	}
+
+	/// Erase a file
+	pub fn remove(&mut self, slot: usize, pbf: &impl PBF) -> QoobResult<()> {
+		let info = self.slot_info(slot)?;
+		let range = slot..slot + info.sector_count(self.geometry());
+		self.erase_range(range.clone(), pbf)?;
+
+		for i in range {
+			self.sector_map[i] = SectorOccupancy::Empty;
+		}
+		self.toc.remove(&slot);
+
+		Ok(())
+	}
+
+	/// Erase a range of sectors, skipping those that are already blank
+	///
+	/// Only sectors the map says are empty are checked, as their header being blank doesn't
+	/// mean the rest is. Reading those holding files or unrecognized data first would be a waste.
+	fn erase_range(&self, range: std::ops::Range<usize>, pbf: &impl PBF) -> QoobResult<()> {
+		track(pbf.create(range.len(), "Erasing", Some(" sectors")), |pb| {
+			let guard = self.dev.lock()?;
+			for sector in range {
+				let dirty = !matches!(self.sector_map[sector], SectorOccupancy::Empty)
+					|| !self.dev.is_blank_locked(&guard, sector)?;
+				if dirty {
+					self.dev.erase_locked(&guard, sector..sector + 1, &())?;
+				}
+				pb.inc(1);
+			}
+			guard.release()
+		})
+	}
+
+	/// Erase a range of sectors, along with the full extent of any file starting within it
+	///
+	/// Fails with [`QoobError::RangeOccupied`] if a file starting before the range extends into it.
+	pub fn clear(&mut self, range: std::ops::Range<usize>, pbf: &impl PBF) -> QoobResult<()> {
+		if range.end > self.geometry().sector_count {
+			return Err(QoobError::TooBig);
+		}
+
+		let mut end = range.end;
+		for i in range.clone() {
+			match self.sector_map[i] {
+				SectorOccupancy::Slot(n) if n < range.start => {
+					return Err(QoobError::RangeOccupied);
+				}
+				SectorOccupancy::Slot(n) => {
+					end = end.max(n + self.toc[&n].sector_count(self.geometry()))
+				}
+				SectorOccupancy::Empty | SectorOccupancy::Unknown => {}
+			}
+		}
+
+		let range = range.start..end;
+		self.erase_range(range.clone(), pbf)?;
+
+		for i in range {
+			self.sector_map[i] = SectorOccupancy::Empty;
+			self.toc.remove(&i);
+		}
+
+		Ok(())
+	}
+
+	/// Check that a range of sectors is truly blank on the flash
+	///
+	/// Unlike [`QoobFs::check_dest_range`], this reads the sectors back
+	/// instead of relying on the scan.
+	pub fn verify_empty(&self, range: std::ops::Range<usize>, pbf: &impl PBF) -> QoobResult<bool> {
+		if range.end > self.geometry().sector_count {
+			return Err(QoobError::TooBig);
+		}
+		track(
+			pbf.create(range.len(), "Checking", Some(" sectors")),
+			|pb| {
+				let guard = self.dev.lock()?;
+				for sector in range {
+					if !self.dev.is_blank_locked(&guard, sector)? {
+						return Ok(false);
+					}
+					pb.inc(1);
+				}
+				guard.release()?;
+				Ok(true)
+			},
+		)
+	}
+
+	/// How much space removing a file would free up
+	///
+	/// Returns the number of sectors freed,
+	/// and the length of the longest run of free sectors after removal.
+	pub fn removal_gain(&self, slot: usize) -> Option<(usize, usize)> {
+		let info = self.toc.get(&slot)?;
+		let freed = slot..slot + info.sector_count(self.geometry());
+		let free_after = self
+			.sector_map
+			.iter()
+			.enumerate()
+			.map(|(i, s)| freed.contains(&i) || matches!(s, SectorOccupancy::Empty));
+		Some((info.sector_count(self.geometry()), longest_run(free_after)))
+	}
+
+	/// Plan how to pack files towards the start of flash, without touching it
+	///
+	/// Files keep their order. Sectors with unrecognized contents are left in place,
+	/// and files are moved around them.
+	pub fn defrag_plan(&self) -> Vec<Move> {
+		let mut moves = Vec::new();
+		let mut cursor = 0;
+		// The table of contents is ordered by slot
+		for &from in self.toc.keys() {
+			let sectors = self.toc[&from].sector_count(self.geometry());
+			// Every file before this one now ends before the cursor,
+			// so only unknown sectors can be in the way
+			let to = (cursor..=from)
+				.find(|&to| {
+					!self.sector_map[to..to + sectors]
+						.iter()
+						.any(|s| matches!(s, SectorOccupancy::Unknown))
+				})
+				.unwrap();
+			if to != from {
+				moves.push(Move { from, to, sectors });
+			}
+			cursor = to + sectors;
+		}
+		moves
+	}
+
+	/// Pack files towards the start of flash, eliminating gaps between them
+	///
+	/// Follows [`QoobFs::defrag_plan`], moving files in order so none of them
+	/// is overwritten before it's relocated. Returns the moves that were made.
+	///
+	/// A file is only held in memory while it's moved,
+	/// so it's lost if the process is interrupted between erasing and rewriting it.
+	pub fn defragment(&mut self, pbf: &impl PBF) -> QoobResult<Vec<Move>> {
+		let moves = self.defrag_plan();
+		for m in &moves {
+			self.relocate(m.from, m.to, pbf)?;
+		}
+		Ok(moves)
+	}
+
+	/// Change the description of a file
+	///
+	/// Only the file's first sector is erased and rewritten.
+	pub fn set_description(
+		&mut self,
+		slot: usize,
+		description: &str,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		self.slot_info(slot)?;
+		let description = encode_description(description)?;
+
+		let offset = self.geometry().sector_to_offset(slot);
+		let mut sector = vec![0; self.geometry().sector_size];
+		let guard = self.dev.lock()?;
+		self.dev.read_locked(&guard, offset, &mut sector, pbf)?;
+		sector[0x04..=0xF7].copy_from_slice(&description);
+		self.dev.erase_locked(&guard, slot..slot + 1, pbf)?;
+		self.dev.write_locked(&guard, offset, &sector, pbf)?;
+		guard.release()?;
+
+		let header = Header(sector[0..HEADER_SIZE].try_into().unwrap());
+		self.toc.insert(slot, header);
+		Ok(())
+	}
+
+	/// Copy a file to another slot
+	///
+	/// The destination must be free: [`WriteOptions::overwrite`] is ignored,
+	/// and copying a file onto itself fails with [`QoobError::SameSlot`].
+	/// The header is copied as is, so [`WriteOptions::pad_size`] is ignored too.
+	pub fn copy_file(
+		&mut self,
+		from: usize,
+		to: usize,
+		options: WriteOptions,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		if from == to {
+			return Err(QoobError::SameSlot);
+		}
+		let data = self.read_sized(from, pbf)?;
+		let options = WriteOptions {
+			pad_size: false,
+			overwrite: false,
+			..options
+		};
+		self.write(to, &data, options, pbf)
+	}
+
+	/// Make two files trade places
+	///
+	/// The file in `a` ends up starting at `b` and vice versa.
+	/// Fails with [`QoobError::SwapOverlap`] if the files would then overlap each other,
+	/// or [`QoobError::RangeOccupied`] if either would overlap anything else.
+	///
+	/// Both files are only held in memory while they're moved,
+	/// so they're lost if the process is interrupted before they're rewritten.
+	pub fn swap_files(&mut self, a: usize, b: usize, pbf: &impl PBF) -> QoobResult<()> {
+		let sectors_a = self.slot_info(a)?.sector_count(self.geometry());
+		let sectors_b = self.slot_info(b)?.sector_count(self.geometry());
+		if a == b {
+			return Ok(());
+		}
+		let old_a = a..a + sectors_a;
+		let old_b = b..b + sectors_b;
+		let new_a = b..b + sectors_a;
+		let new_b = a..a + sectors_b;
+		if new_a.end > self.geometry().sector_count || new_b.end > self.geometry().sector_count {
+			return Err(QoobError::TooBig);
+		}
+		if new_a.start < new_b.end && new_b.start < new_a.end {
+			return Err(QoobError::SwapOverlap);
+		}
+		for i in new_a.clone().chain(new_b.clone()) {
+			match self.sector_map[i] {
+				SectorOccupancy::Empty => {}
+				SectorOccupancy::Slot(n) if n == a || n == b => {}
+				SectorOccupancy::Slot(_) | SectorOccupancy::Unknown => {
+					return Err(QoobError::RangeOccupied);
+				}
+			}
+		}
+
+		let data_a = self.read(a, pbf)?;
+		let data_b = self.read(b, pbf)?;
+		let affected = |i: usize| {
+			[&old_a, &old_b, &new_a, &new_b]
+				.iter()
+				.any(|r| r.contains(&i))
+		};
+		for run in runs((0..self.geometry().sector_count).map(affected)) {
+			self.dev.erase(run, pbf)?;
+		}
+		self.dev
+			.write(self.geometry().sector_to_offset(b), &data_a, pbf)?;
+		self.dev
+			.write(self.geometry().sector_to_offset(a), &data_b, pbf)?;
+
+		let header_a = self.toc.remove(&a).unwrap();
+		let header_b = self.toc.remove(&b).unwrap();
+		for i in old_a.chain(old_b) {
+			self.sector_map[i] = SectorOccupancy::Empty;
+		}
+		for i in new_a {
+			self.sector_map[i] = SectorOccupancy::Slot(b);
+		}
+		for i in new_b {
+			self.sector_map[i] = SectorOccupancy::Slot(a);
+		}
+		self.toc.insert(b, header_a);
+		self.toc.insert(a, header_b);
+		Ok(())
+	}
+
+	/// Move a file to another slot
+	///
+	/// The destination may overlap the file's current location,
+	/// but not any other file or unrecognized data.
+	pub fn move_file(&mut self, from: usize, to: usize, pbf: &impl PBF) -> QoobResult<()> {
+		let sectors = self.slot_info(from)?.sector_count(self.geometry());
+		if from == to {
+			return Ok(());
+		}
+		if to
+			.checked_add(sectors)
+			.is_none_or(|end| end > self.geometry().sector_count)
+		{
+			return Err(QoobError::TooBig);
+		}
+		for i in to..to + sectors {
+			match self.sector_map[i] {
+				SectorOccupancy::Empty => {}
+				SectorOccupancy::Slot(n) if n == from => {}
+				SectorOccupancy::Slot(_) | SectorOccupancy::Unknown => {
+					return Err(QoobError::RangeOccupied);
+				}
+			}
+		}
+		self.relocate(from, to, pbf)
+	}
+
+	/// Move a file to another slot, with no checks on the destination
+	///
+	/// The source and destination ranges may overlap.
+	fn relocate(&mut self, from: usize, to: usize, pbf: &impl PBF) -> QoobResult<()> {
+		let sectors = self.slot_info(from)?.sector_count(self.geometry());
+		let data = self.read(from, pbf)?;
+
+		let source = from..from + sectors;
+		let dest = to..to + sectors;
+		if source.start < dest.end && dest.start < source.end {
+			self.dev
+				.erase(source.start.min(dest.start)..source.end.max(dest.end), pbf)?;
+		} else {
+			self.dev.erase(source.clone(), pbf)?;
+			self.dev.erase(dest.clone(), pbf)?;
+		}
+		self.dev
+			.write(self.geometry().sector_to_offset(to), &data, pbf)?;
+
+		let header = self.toc.remove(&from).unwrap();
+		for i in source {
+			self.sector_map[i] = SectorOccupancy::Empty;
+		}
+		for i in dest {
+			self.sector_map[i] = SectorOccupancy::Slot(to);
+		}
+		self.toc.insert(to, header);
+		Ok(())
+	}
+
+	/// The sectors `len` bytes starting at `slot` would span
+	///
+	/// Fails with [`QoobError::TooBig`] if the end can't even be represented.
+	/// Whether it fits in flash is up to [`QoobFs::check_dest_range`].
+	fn file_range(&self, slot: usize, len: usize) -> QoobResult<std::ops::Range<usize>> {
+		let end = slot
+			.checked_add(self.geometry().size_to_sectors(len))
+			.ok_or(QoobError::TooBig)?;
+		Ok(slot..end)
+	}
+
+	/// Check whether it's possible to write to a given range
+	pub fn check_dest_range(&self, range: std::ops::Range<usize>) -> RangeCheck {
+		if range.end > self.geometry().sector_count {
+			return RangeCheck::Overflow;
+		}
+
+		let mut status = RangeCheck::Empty;
+		for i in range.clone() {
+			match self.sector_map[i] {
+				SectorOccupancy::Empty => {}
+				SectorOccupancy::Unknown => {
+					status = RangeCheck::Occupied;
+				}
+				SectorOccupancy::Slot(i) if i == range.start => {
+					status = RangeCheck::Occupied;
+				}
+				SectorOccupancy::Slot(_) => return RangeCheck::Overlap,
+			}
+		}
+		status
+	}
+
+	/// Work out where a file would go and what's in the way, without touching flash
+	pub fn plan_write(&self, slot: usize, data: &[u8]) -> QoobResult<WritePlan> {
+		let header = validate_header(data, self.geometry()).ok_or(QoobError::InvalidHeader)?;
+		let range = self.file_range(slot, data.len())?;
+		let check = self.check_dest_range(range.clone());
+		Ok(WritePlan {
+			header,
+			range,
+			check,
+			needs_erase: check == RangeCheck::Occupied,
+		})
+	}
+
+	/// Write a new file, optionally verifying the written data
+	pub fn write(
+		&mut self,
+		slot: usize,
+		data: &[u8],
+		options: WriteOptions,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		if options.strict_size {
+			check_size(data)?;
+		}
+		let data = prepare_file(data, options.pad_size, self.geometry())?;
+
+		let dest_range = self.file_range(slot, data.len())?;
+		match self.check_dest_range(dest_range.clone()) {
+			RangeCheck::Empty => Ok(()),
+			RangeCheck::Overflow => Err(QoobError::TooBig),
+			RangeCheck::Occupied if options.overwrite => self.clear(dest_range.clone(), pbf),
+			RangeCheck::Occupied | RangeCheck::Overlap => Err(QoobError::RangeOccupied),
+		}?;
+
+		let offset = self.geometry().sector_to_offset(slot);
+		self.write_data(offset, &data, options.verify, pbf)?;
+
+		for i in dest_range {
+			self.sector_map[i] = SectorOccupancy::Slot(slot);
+		}
+		let header = Header(data[0..HEADER_SIZE].try_into().unwrap());
+		self.toc.insert(slot, header);
+
+		Ok(())
+	}
+
+	/// Finish a write that was interrupted, e.g. one that failed with [`QoobError::Cancelled`]
+	///
+	/// `data` and `options` must be the same as for the original [`QoobFs::write`].
+	/// Sectors before `from_sector` are assumed to be written already, but the header and the
+	/// last of them are read back and compared first, failing with
+	/// [`QoobError::VerificationMismatch`] if the original write didn't actually get that far.
+	/// The rest of the destination is erased where needed and written again.
+	///
+	/// Resuming from `slot` itself is a fresh start: unless the header there is already this
+	/// file's, the destination must be empty, or [`WriteOptions::overwrite`] set to clear it.
+	/// Unrecognized data in the destination always requires [`WriteOptions::overwrite`].
+	pub fn resume_write(
+		&mut self,
+		slot: usize,
+		data: &[u8],
+		from_sector: usize,
+		options: WriteOptions,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		if options.strict_size {
+			check_size(data)?;
+		}
+		let geometry = self.geometry();
+		let data = prepare_file(data, options.pad_size, self.geometry())?;
+
+		let dest_range = self.file_range(slot, data.len())?;
+		let check = self.check_dest_range(dest_range.clone());
+		match check {
+			RangeCheck::Empty | RangeCheck::Occupied => Ok(()),
+			RangeCheck::Overflow => Err(QoobError::TooBig),
+			RangeCheck::Overlap => Err(QoobError::RangeOccupied),
+		}?;
+		if !dest_range.contains(&from_sector) {
+			return Err(QoobError::OutOfBounds {
+				offset: from_sector.saturating_mul(geometry.sector_size),
+				len: geometry.sector_size,
+			});
+		}
+		let unknown = dest_range
+			.clone()
+			.any(|i| matches!(self.sector_map[i], SectorOccupancy::Unknown));
+		if unknown && !options.overwrite {
+			return Err(QoobError::RangeOccupied);
+		}
+
+		let resume_at = geometry.sector_to_offset(from_sector - slot);
+		if from_sector > slot {
+			// Make sure the original write really got this far
+			self.check_written(geometry.sector_to_offset(slot), &data[..HEADER_SIZE])?;
+			self.check_written(
+				geometry.sector_to_offset(from_sector - 1),
+				&data[resume_at - geometry.sector_size..resume_at],
+			)?;
+		} else if check == RangeCheck::Occupied
+			&& self.raw_header(slot)?.as_bytes()[..] != data[..HEADER_SIZE]
+		{
+			// Something other than this file is in the way
+			if !options.overwrite {
+				return Err(QoobError::RangeOccupied);
+			}
+			self.clear(dest_range.clone(), pbf)?;
+		}
+
+		self.dev.erase_if_dirty(from_sector..dest_range.end, pbf)?;
+		let offset = geometry.sector_to_offset(from_sector);
+		self.write_data(offset, &data[resume_at..], options.verify, pbf)?;
+
+		// Whatever the map still had at `slot` wasn't erased past the new file's end
+		for occupancy in &mut self.sector_map {
+			if matches!(occupancy, SectorOccupancy::Slot(n) if *n == slot) {
+				*occupancy = SectorOccupancy::Unknown;
+			}
+		}
+		for i in dest_range {
+			self.sector_map[i] = SectorOccupancy::Slot(slot);
+		}
+		let header = Header(data[0..HEADER_SIZE].try_into().unwrap());
+		self.toc.insert(slot, header);
+
+		Ok(())
+	}
+
+	/// Fail with [`QoobError::VerificationMismatch`] unless flash holds `expected` at `offset`
+	fn check_written(&self, offset: usize, expected: &[u8]) -> QoobResult<()> {
+		let mut found = vec![0; expected.len()];
+		self.dev.read(offset, &mut found, &())?;
+		match found.iter().zip(expected).position(|(f, e)| f != e) {
+			Some(i) => Err(QoobError::VerificationMismatch {
+				offset: offset + i,
+				expected: expected[i],
+				found: found[i],
+			}),
+			None => Ok(()),
+		}
+	}
+
+	/// Write data to flash, checking it as specified
+	fn write_data(
+		&self,
+		offset: usize,
+		data: &[u8],
+		verify: VerifyMode,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		match verify {
+			VerifyMode::Off => self.dev.write(offset, data, pbf),
+			VerifyMode::Readback => self.dev.write_verified(offset, data, pbf),
+			VerifyMode::StatusOnly => self.dev.write_checked(offset, data, pbf),
+			VerifyMode::Checksum => {
+				self.dev.write(offset, data, pbf)?;
+				let mut expected = Crc32::new();
+				expected.update(data);
+				let mut actual = Crc32::new();
+				self.dev
+					.read_to_writer(offset, data.len(), &mut actual, pbf)?;
+				if actual.value() != expected.value() {
+					return Err(QoobError::VerificationError);
+				}
+				Ok(())
+			}
+		}
+	}
+
+	/// Find the first run of `sectors` empty sectors
+	pub fn find_free_slot(&self, sectors: usize) -> Option<usize> {
+		(0..=self.geometry().sector_count.checked_sub(sectors)?).find(|&slot| {
+			self.sector_map[slot..slot + sectors]
+				.iter()
+				.all(|s| matches!(s, SectorOccupancy::Empty))
+		})
+	}
+
+	/// Write a new file to the first slot with enough room for it
+	///
+	/// Returns the chosen slot, or [`QoobError::NoSpace`] if nothing fits.
+	pub fn write_auto(
+		&mut self,
+		data: &[u8],
+		options: WriteOptions,
+		pbf: &impl PBF,
+	) -> QoobResult<usize> {
+		validate_header(data, self.geometry()).ok_or(QoobError::InvalidHeader)?;
+		let slot = self
+			.find_free_slot(self.geometry().size_to_sectors(data.len()))
+			.ok_or(QoobError::NoSpace)?;
+		self.write(slot, data, options, pbf)?;
+		Ok(slot)
+	}
+
+	/// Write a payload with no Qoob header, generating one for it
+	///
+	/// The header always holds the exact size, regardless of [`WriteOptions::pad_size`],
+	/// so [`QoobFs::read_file`] gives back exactly `payload`.
+	pub fn write_payload(
+		&mut self,
+		slot: usize,
+		payload: &[u8],
+		file_type: FileType,
+		description: &str,
+		options: WriteOptions,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		let file = wrap_payload(file_type, description, payload)?;
+		let options = WriteOptions {
+			pad_size: false,
+			..options
+		};
+		self.write(slot, &file, options, pbf)
+	}
+
+	/// Write several payloads, choosing a slot for each, as with [`QoobFs::write_payload`]
+	///
+	/// Placement is planned up front, largest file first, each going in the first run of free
+	/// sectors it fits in. Fails with [`QoobError::NoSpace`] before writing anything if they
+	/// can't all fit.
+	/// Returns the chosen slots, in the same order as `files`.
+	pub fn write_many(
+		&mut self,
+		files: &[(FileType, String, Vec<u8>)],
+		options: WriteOptions,
+		pbf: &impl PBF,
+	) -> QoobResult<Vec<usize>> {
+		let files = files
+			.iter()
+			.map(|(file_type, description, payload)| wrap_payload(*file_type, description, payload))
+			.collect::<QoobResult<Vec<_>>>()?;
+
+		let mut free: Vec<bool> = self
+			.sector_map
+			.iter()
+			.map(|s| matches!(s, SectorOccupancy::Empty))
+			.collect();
+		let mut order: Vec<usize> = (0..files.len()).collect();
+		order.sort_by_key(|&i| std::cmp::Reverse(files[i].len()));
+		let mut slots = vec![0; files.len()];
+		for i in order {
+			let sectors = self.geometry().size_to_sectors(files[i].len());
+			let slot = (0..=free.len().checked_sub(sectors).ok_or(QoobError::NoSpace)?)
+				.find(|&slot| free[slot..slot + sectors].iter().all(|&f| f))
+				.ok_or(QoobError::NoSpace)?;
+			free[slot..slot + sectors].fill(false);
+			slots[i] = slot;
+		}
+
+		let options = WriteOptions {
+			pad_size: false,
+			..options
+		};
+		for (file, &slot) in files.iter().zip(&slots) {
+			self.write(slot, file, options, pbf)?;
+		}
+		Ok(slots)
+	}
+
+	/// Store arbitrary data as a Swiss file, generating its header
+	///
+	/// `name` ends up in the description field.
+	/// As with [`QoobFs::write_payload`], the exact size is kept,
+	/// so [`QoobFs::read_file`] gives back exactly `data`.
+	pub fn write_swiss(
+		&mut self,
+		slot: usize,
+		name: &str,
+		data: &[u8],
+		options: WriteOptions,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		self.write_payload(slot, data, FileType::Swiss, name, options, pbf)
+	}
+
+	/// Compare the whole flash with an image, listing the sectors that differ
+	pub fn diff_image(&self, image: &[u8], pbf: &impl PBF) -> QoobResult<Vec<SectorDiff>> {
+		let geometry = self.geometry();
+		if image.len() != geometry.flash_size() {
+			return Err(QoobError::ImageSize {
+				expected: self.geometry().flash_size(),
+				actual: image.len(),
+			});
+		}
+		let pb = pbf.create(image.len(), "Comparing", None);
+		track(pb, |pb| {
+			let guard = self.dev.lock()?;
+			let mut diffs = Vec::new();
+			let mut current = vec![0; geometry.sector_size];
+			for (sector, expected) in image.chunks(geometry.sector_size).enumerate() {
+				let offset = geometry.sector_to_offset(sector);
+				self.dev.read_chunked(offset, &mut current, pb)?;
+				if let Some(i) = current.iter().zip(expected).position(|(a, e)| a != e) {
+					diffs.push(SectorDiff {
+						sector,
+						first_difference: offset + i,
+					});
+				}
+			}
+			guard.release()?;
+			Ok(diffs)
+		})
+	}
+
+	/// Compare a file with the contents of a slot
+	///
+	/// `data` is compared as [`QoobFs::write`] would store it,
+	/// with the size padded unless the slot's header holds the exact size of `data`.
+	/// Reading stops at the first difference.
+	pub fn compare_file(
+		&self,
+		slot: usize,
+		data: &[u8],
+		pbf: &impl PBF,
+	) -> QoobResult<FileComparison> {
+		let info = self.slot_info(slot)?;
+		let data = prepare_file(data, info.size() != data.len(), self.geometry())?;
+		let slot_size = self
+			.geometry()
+			.sector_to_offset(info.sector_count(self.geometry()));
+		let len = data.len().min(slot_size);
+
+		let mut first_difference = track(pbf.create(len, "Comparing", None), |pb| {
+			let guard = self.dev.lock()?;
+			let mut buf = vec![0; self.dev.transfer_size()];
+			let mut first_difference = None;
+			let mut cursor = 0;
+			for expected in data[..len].chunks(self.dev.transfer_size()) {
+				let actual = &mut buf[..expected.len()];
+				self.dev
+					.read_raw(self.geometry().sector_to_offset(slot) + cursor, actual, pb)?;
+				if let Some(i) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+					first_difference = Some(cursor + i);
+					break;
+				}
+				cursor += expected.len();
+			}
+			guard.release()?;
+			Ok(first_difference)
+		})?;
+
+		if first_difference.is_none() && data.len() > slot_size {
+			first_difference = Some(slot_size);
+		}
+
+		Ok(FileComparison {
+			size_matches: self.geometry().size_to_sectors(data.len())
+				== info.sector_count(self.geometry()),
+			first_difference,
+		})
+	}
+
+	/// Describe the current layout, without the data
+	///
+	/// Each file is given a path named after its slot, to be adjusted as needed.
+	pub fn export_manifest(&self) -> Manifest {
+		let entries = self
+			.iter_files()
+			.map(|(slot, header)| ManifestEntry {
+				slot,
+				file_type: header.r#type(),
+				description: header.description_lossy(),
+				path: format!("slot{slot:02}.bin").into(),
+			})
+			.collect();
+		Manifest { entries }
+	}
+
+	/// Write the files listed in a manifest, using `resolve` to load each of them
+	///
+	/// Every file is loaded and checked against the manifest and the current contents of flash
+	/// before anything is written, so a missing file or a conflict doesn't leave a job half done.
+	/// Problems are reported as [`QoobError::ManifestSlot`].
+	/// The descriptions in the manifest replace those in the files' headers,
+	/// unless they match already: a description that isn't valid UTF-8 is then kept byte for byte
+	/// rather than re-encoded.
+	pub fn apply_manifest(
+		&mut self,
+		manifest: &Manifest,
+		mut resolve: impl FnMut(&ManifestEntry) -> QoobResult<Vec<u8>>,
+		options: WriteOptions,
+		pbf: &impl PBF,
+	) -> QoobResult<()> {
+		let mut claimed = vec![false; self.sector_map.len()];
+		let mut files = Vec::new();
+		for entry in &manifest.entries {
+			let mut prepare = || {
+				// Don't bother loading a file that can't go anywhere
+				self.geometry()
+					.check_sectors(entry.slot..entry.slot.saturating_add(1))?;
+				let mut data = resolve(entry)?;
+				let header =
+					validate_header(&data, self.geometry()).ok_or(QoobError::InvalidHeader)?;
+				if header.r#type() != entry.file_type {
+					return Err(QoobError::InvalidHeader);
+				}
+				if header.description_lossy() != entry.description {
+					data[0x04..=0xF7].copy_from_slice(&encode_description(&entry.description)?);
+				}
+
+				let range = self.file_range(entry.slot, data.len())?;
+				match self.check_dest_range(range.clone()) {
+					RangeCheck::Empty => Ok(()),
+					RangeCheck::Overflow => Err(QoobError::TooBig),
+					RangeCheck::Occupied | RangeCheck::Overlap => Err(QoobError::RangeOccupied),
+				}?;
+				// Entries can't overlap each other either
+				if claimed[range.clone()].iter().any(|&c| c) {
+					return Err(QoobError::RangeOccupied);
+				}
+				claimed[range].fill(true);
+				Ok(data)
+			};
+			let data = prepare().map_err(|error| QoobError::ManifestSlot {
+				slot: entry.slot,
+				error: Box::new(error),
+			})?;
+			files.push((entry.slot, data));
+		}
+
+		for (slot, data) in files {
+			self.write(slot, &data, options, pbf)?;
+		}
+		Ok(())
+	}
+
+	/// Retrieve the underlying device handle
+	pub fn into_device(self) -> QoobDevice {
+		self.dev
+	}
+}
+
+/// Shorthands for callers that don't display progress
+impl QoobFs {
+	/// [`QoobFs::read`] without progress reporting
+	pub fn read_silent(&self, slot: usize) -> QoobResult<Vec<u8>> {
+		self.read(slot, &())
+	}
+
+	/// [`QoobFs::read_file`] without progress reporting
+	pub fn read_file_silent(&self, slot: usize) -> QoobResult<Vec<u8>> {
+		self.read_file(slot, &())
+	}
+
+	/// [`QoobFs::write`] without progress reporting
+	pub fn write_silent(
+		&mut self,
+		slot: usize,
+		data: &[u8],
+		options: WriteOptions,
+	) -> QoobResult<()> {
+		self.write(slot, data, options, &())
+	}
+
+	/// [`QoobFs::remove`] without progress reporting
+	pub fn remove_silent(&mut self, slot: usize) -> QoobResult<()> {
+		self.remove(slot, &())
+	}
+}
+
+/// Interpret a header found at `sector`, if it describes a file that fits in flash
+fn plausible_header(
+	sector: usize,
+	header: [u8; HEADER_SIZE],
+	geometry: Geometry,
+) -> Option<Header> {
+	let file = Header(header);
+	(!matches!(file.r#type(), FileType::Unknown(_))
+		&& file.size() >= HEADER_SIZE
+		&& file.sector_count(geometry) <= geometry.sector_count - sector)
+		.then_some(file)
+}
+
+/// Length of the longest run of `true` values
+fn longest_run(iter: impl Iterator<Item = bool>) -> usize {
+	let mut longest = 0;
+	let mut current = 0;
+	for b in iter {
+		current = if b { current + 1 } else { 0 };
+		longest = longest.max(current);
+	}
+	longest
+}
+
+/// The ranges of indices where `iter` yields `true`
+fn runs(iter: impl Iterator<Item = bool>) -> Vec<std::ops::Range<usize>> {
+	let mut runs: Vec<std::ops::Range<usize>> = Vec::new();
+	for (i, b) in iter.enumerate() {
+		if !b {
+			continue;
+		}
+		match runs.last_mut() {
+			Some(run) if run.end == i => run.end += 1,
+			_ => runs.push(i..i + 1),
+		}
+	}
+	runs
+}
+
+/// Turn a string into a NUL-padded description field
+fn encode_description(description: &str) -> QoobResult<[u8; DESCRIPTION_SIZE]> {
+	let description = description.as_bytes();
+	if description.len() > DESCRIPTION_SIZE || description.contains(&0) {
+		return Err(QoobError::InvalidDescription);
+	}
+	let mut field = [0; DESCRIPTION_SIZE];
+	field[..description.len()].copy_from_slice(description);
+	Ok(field)
+}
+
+/// Prepend a freshly generated header to a payload
+fn wrap_payload(file_type: FileType, description: &str, payload: &[u8]) -> QoobResult<Vec<u8>> {
+	let header = Header::builder()
+		.file_type(file_type)
+		.description(description)
+		.payload_len(payload.len())
+		.build()?;
+	let mut file = header.as_bytes().to_vec();
+	file.extend_from_slice(payload);
+	Ok(file)
+}
+
+/// Validate a file and turn it into the exact bytes [`QoobFs::write`] stores
+fn prepare_file(data: &[u8], pad_size: bool, geometry: Geometry) -> QoobResult<Vec<u8>> {
+	let header = validate_header(data, geometry).ok_or(QoobError::InvalidHeader)?;
+
+	let mut data = data.to_vec();
+	if pad_size {
+		data[0xFC..=0xFF].copy_from_slice(&padded_size(&header, geometry));
+	}
+	Ok(data)
+}
+
+/// A header's size field, rounded up to whole sectors
+fn padded_size(header: &Header, geometry: Geometry) -> [u8; 4] {
+	// The size is specified to be a multiple of the sector size
+	u32::to_be_bytes(geometry.sector_to_offset(header.sector_count(geometry)) as _)
 }
 
-/// Validate a file header
-pub fn validate_header(data: &[u8]) -> Option<Header> {
-	if data.len() < HEADER_SIZE {
-		return None;
+/// Check that a file's header declares exactly the file's length
+fn check_size(data: &[u8]) -> QoobResult<()> {
+	let header = data.get(..HEADER_SIZE).ok_or(QoobError::InvalidHeader)?;
+	let header = Header(header.try_into().unwrap());
+	if header.size() != data.len() {
+		return Err(QoobError::SizeMismatch {
+			declared: header.size(),
+			actual: data.len(),
+		});
 	}
-	let header = Header(data[0..HEADER_SIZE].try_into().unwrap());
+	Ok(())
+}
+
+/// Validate the header of a file meant for a chip laid out like `geometry`
+pub fn validate_header(data: &[u8], geometry: Geometry) -> Option<Header> {
+	let header = data.get(..HEADER_SIZE)?;
+	check_header(header.try_into().unwrap(), data.len(), geometry)
+}
+
+/// Validate the header of a file that's `len` bytes long
+fn check_header(header: [u8; HEADER_SIZE], len: usize, geometry: Geometry) -> Option<Header> {
+	let header = Header(header);
 
-	let sector_count = device::size_to_sectors(data.len());
+	let sector_count = geometry.size_to_sectors(len);
 	let size_valid =
-		header.size() == data.len() || header.size() == sector_count * device::SECTOR_SIZE;
+		header.size() == len || header.size() == geometry.sector_to_offset(sector_count);
 
 	(size_valid && !matches!(header.r#type(), FileType::Unknown(_))).then_some(header)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::{self, SECTOR_COUNT};
+	use crate::transport::mock::MockFlash;
+
+	fn mount(flash: MockFlash) -> QoobFs {
+		QoobFs::from_device(QoobDevice::from_transport(Box::new(flash)), &()).unwrap()
+	}
+
+	/// A Swiss file spanning `sectors` sectors, its payload filled with `fill`
+	fn swiss_file(sectors: usize, fill: u8) -> Vec<u8> {
+		let payload = vec![fill; sectors * device::SECTOR_SIZE - HEADER_SIZE];
+		wrap_payload(FileType::Swiss, "test", &payload).unwrap()
+	}
+
+	#[test]
+	fn write_to_last_slot() {
+		let mut fs = mount(MockFlash::blank());
+		let last = SECTOR_COUNT - 1;
+		let data = swiss_file(1, 0x5A);
+		fs.write(last, &data, WriteOptions::default(), &()).unwrap();
+		assert_eq!(fs.read(last, &()).unwrap(), data);
+
+		fs.scan(&()).unwrap();
+		assert_eq!(fs.slot_info(last).unwrap().r#type(), FileType::Swiss);
+		assert!(matches!(fs.iter_slots().last(), Some(SectorOccupancy::Slot(n)) if *n == last));
+	}
+
+	#[test]
+	fn scan_full_flash_bios() {
+		let mut image = vec![0; device::FLASH_SIZE];
+		image[0..4].copy_from_slice(&FileType::Bios.magic());
+		image[0xFC..=0xFF].copy_from_slice(&u32::to_be_bytes(device::FLASH_SIZE as _));
+		let fs = mount(MockFlash::new(image));
+
+		let report = fs.scan_report();
+		assert_eq!(report.files, 1);
+		assert_eq!(report.empty_sectors, 0);
+		assert!(report.unknown_sectors.is_empty());
+		assert!(report.conflicts.is_empty());
+		assert_eq!(fs.slot_info(0).unwrap().r#type(), FileType::Bios);
+		assert_eq!(fs.occupied_sectors(0), Some(SECTOR_COUNT));
+	}
+
+	#[test]
+	fn read_file_keeps_bios_header() {
+		let mut fs = mount(MockFlash::blank());
+		let mut bios = vec![0x42; 2 * device::SECTOR_SIZE];
+		bios[0..4].copy_from_slice(&FileType::Bios.magic());
+		let size = u32::to_be_bytes(bios.len() as _);
+		bios[0xFC..=0xFF].copy_from_slice(&size);
+		fs.write(0, &bios, WriteOptions::default(), &()).unwrap();
+		assert_eq!(fs.read_file(0, &()).unwrap(), bios);
+
+		let file = swiss_file(1, 0x11);
+		fs.write(2, &file, WriteOptions::default(), &()).unwrap();
+		assert_eq!(fs.read_file(2, &()).unwrap(), file[HEADER_SIZE..]);
+	}
+
+	#[test]
+	fn detect_rejects_dol_section_past_the_end() {
+		let mut dol = vec![0; 0x200];
+		dol[0xE0..0xE4].copy_from_slice(&0x8000_3100u32.to_be_bytes());
+		dol[0x00..0x04].copy_from_slice(&0x100u32.to_be_bytes());
+		dol[0x48..0x4C].copy_from_slice(&0x8000_3100u32.to_be_bytes());
+		dol[0x90..0x94].copy_from_slice(&0x100u32.to_be_bytes());
+		assert_eq!(FileType::detect(&dol), FileType::Elf);
+
+		// A size that wraps around when added to the offset
+		dol[0x90..0x94].copy_from_slice(&u32::MAX.to_be_bytes());
+		assert_eq!(FileType::detect(&dol), FileType::Swiss);
+	}
+
+	#[test]
+	fn copy_never_overwrites() {
+		let mut fs = mount(MockFlash::blank());
+		let a = swiss_file(1, 0xAA);
+		let b = swiss_file(1, 0xBB);
+		fs.write(0, &a, WriteOptions::default(), &()).unwrap();
+		fs.write(1, &b, WriteOptions::default(), &()).unwrap();
+		let options = WriteOptions {
+			overwrite: true,
+			..Default::default()
+		};
+
+		assert!(matches!(
+			fs.copy_file(0, 0, options, &()),
+			Err(QoobError::SameSlot)
+		));
+		assert!(matches!(
+			fs.copy_file(0, 1, options, &()),
+			Err(QoobError::RangeOccupied)
+		));
+		fs.scan(&()).unwrap();
+		assert_eq!(fs.read(0, &()).unwrap(), a);
+		assert_eq!(fs.read(1, &()).unwrap(), b);
+
+		fs.copy_file(0, 2, options, &()).unwrap();
+		assert_eq!(fs.read(2, &()).unwrap(), a);
+	}
+
+	#[test]
+	fn clear_erases_hidden_data() {
+		let mut image = vec![0xFF; device::FLASH_SIZE];
+		// Data past a blank header, as left behind by an interrupted write
+		image[5 * device::SECTOR_SIZE + 1000] = 0;
+		let mut fs = mount(MockFlash::new(image));
+		assert_eq!(fs.scan_report().empty_sectors, SECTOR_COUNT);
+
+		fs.clear(4..7, &()).unwrap();
+		assert!(fs.verify_empty(4..7, &()).unwrap());
+	}
+
+	#[test]
+	fn custom_geometry() {
+		let geometry = Geometry {
+			sector_size: 0x1000,
+			sector_count: 8,
+		};
+		let flash = MockFlash::with_sector_size(vec![0xFF; geometry.flash_size()], 0x1000);
+		let mut dev = QoobDevice::from_transport(Box::new(flash));
+		dev.set_geometry(geometry);
+		let mut fs = QoobFs::from_device(dev, &()).unwrap();
+		assert_eq!(fs.iter_slots().count(), 8);
+
+		let file = wrap_payload(FileType::Swiss, "small", &[0x33; 0x1800]).unwrap();
+		fs.write(3, &file, WriteOptions::default(), &()).unwrap();
+		fs.scan(&()).unwrap();
+		assert_eq!(fs.slot_info(3).unwrap().sector_count(geometry), 2);
+		assert_eq!(fs.occupied_sectors(3), Some(2));
+		// The size is padded to whole sectors of this chip, not the Qoob Pro's
+		assert_eq!(fs.slot_info(3).unwrap().size(), 0x2000);
+		assert!(fs.read_file(3, &()).unwrap().starts_with(&[0x33; 0x1800]));
+		assert!(matches!(
+			fs.write(7, &file, WriteOptions::default(), &()),
+			Err(QoobError::TooBig)
+		));
+	}
+
+	#[test]
+	fn resume_finishes_interrupted_write() {
+		let file = swiss_file(3, 0x44);
+		let mut image = vec![0xFF; device::FLASH_SIZE];
+		image[..device::SECTOR_SIZE].copy_from_slice(&file[..device::SECTOR_SIZE]);
+		let mut fs = mount(MockFlash::new(image));
+
+		fs.resume_write(0, &file, 1, WriteOptions::default(), &())
+			.unwrap();
+		assert_eq!(fs.read(0, &()).unwrap(), file);
+		assert_eq!(fs.occupied_sectors(0), Some(3));
+	}
+
+	#[test]
+	fn resume_requires_started_write() {
+		let mut fs = mount(MockFlash::blank());
+		let file = swiss_file(2, 0x44);
+		assert!(matches!(
+			fs.resume_write(0, &file, 1, WriteOptions::default(), &()),
+			Err(QoobError::VerificationMismatch { offset: 0, .. })
+		));
+
+		// The right header isn't enough if the rest of the sector is missing
+		let mut image = vec![0xFF; device::FLASH_SIZE];
+		image[..HEADER_SIZE].copy_from_slice(&file[..HEADER_SIZE]);
+		let mut fs = mount(MockFlash::new(image));
+		assert!(matches!(
+			fs.resume_write(0, &file, 1, WriteOptions::default(), &()),
+			Err(QoobError::VerificationMismatch {
+				offset: HEADER_SIZE,
+				..
+			})
+		));
+	}
+
+	#[test]
+	fn resume_from_start_replaces_other_file() {
+		let mut fs = mount(MockFlash::blank());
+		fs.write(0, &swiss_file(3, 0x11), WriteOptions::default(), &())
+			.unwrap();
+		let file = swiss_file(1, 0x22);
+		assert!(matches!(
+			fs.resume_write(0, &file, 0, WriteOptions::default(), &()),
+			Err(QoobError::RangeOccupied)
+		));
+
+		let options = WriteOptions {
+			overwrite: true,
+			..Default::default()
+		};
+		fs.resume_write(0, &file, 0, options, &()).unwrap();
+		assert_eq!(fs.occupied_sectors(0), Some(1));
+		assert!(fs
+			.iter_slots()
+			.skip(1)
+			.all(|s| matches!(s, SectorOccupancy::Empty)));
+		fs.scan(&()).unwrap();
+		assert_eq!(fs.read(0, &()).unwrap(), file);
+		assert_eq!(fs.scan_report().empty_sectors, SECTOR_COUNT - 1);
+	}
+
+	#[test]
+	fn resume_over_unknown_requires_overwrite() {
+		let mut image = vec![0xFF; device::FLASH_SIZE];
+		image[device::SECTOR_SIZE..device::SECTOR_SIZE + 4].copy_from_slice(b"junk");
+		let mut fs = mount(MockFlash::new(image));
+		let file = swiss_file(2, 0x33);
+		assert!(matches!(
+			fs.resume_write(0, &file, 0, WriteOptions::default(), &()),
+			Err(QoobError::RangeOccupied)
+		));
+
+		let options = WriteOptions {
+			overwrite: true,
+			..Default::default()
+		};
+		fs.resume_write(0, &file, 0, options, &()).unwrap();
+		assert_eq!(fs.read(0, &()).unwrap(), file);
+	}
+
+	#[test]
+	fn huge_inputs_fail_cleanly() {
+		let mut fs = mount(MockFlash::blank());
+		let file = swiss_file(2, 0x11);
+		fs.write(0, &file, WriteOptions::default(), &()).unwrap();
+		let huge = usize::MAX;
+
+		assert!(matches!(
+			fs.write(huge, &file, WriteOptions::default(), &()),
+			Err(QoobError::TooBig)
+		));
+		assert!(matches!(fs.plan_write(huge, &file), Err(QoobError::TooBig)));
+		assert!(matches!(
+			fs.copy_file(0, huge, WriteOptions::default(), &()),
+			Err(QoobError::TooBig)
+		));
+		assert!(matches!(
+			fs.resume_write(huge, &file, huge, WriteOptions::default(), &()),
+			Err(QoobError::TooBig)
+		));
+		assert!(matches!(
+			fs.writer(huge, file.len()),
+			Err(QoobError::TooBig)
+		));
+		assert!(matches!(fs.move_file(0, huge, &()), Err(QoobError::TooBig)));
+		assert!(matches!(
+			fs.read_sectors_of(huge, 2, &()),
+			Err(QoobError::OutOfBounds { .. })
+		));
+		assert!(matches!(
+			fs.read_sectors_of(0, huge, &()),
+			Err(QoobError::OutOfBounds { .. })
+		));
+		assert!(matches!(
+			fs.raw_header(huge),
+			Err(QoobError::OutOfBounds { .. })
+		));
+	}
+
+	#[test]
+	fn reader_keeps_bus_across_reads() {
+		use std::io::Read;
+
+		let mut fs = mount(MockFlash::blank());
+		let a = swiss_file(1, 0xAA);
+		let b = swiss_file(1, 0xBB);
+		fs.write(0, &a, WriteOptions::default(), &()).unwrap();
+		fs.write(1, &b, WriteOptions::default(), &()).unwrap();
+
+		// The mock panics if flash is read after the inner read gives the bus back
+		let mut reader = fs.reader(0).unwrap();
+		assert_eq!(fs.read(1, &()).unwrap(), b);
+		let mut data = Vec::new();
+		reader.read_to_end(&mut data).unwrap();
+		assert_eq!(data, a);
+	}
+
+	#[test]
+	fn reader_stops_at_end() {
+		use std::io::Read;
+
+		let mut fs = mount(MockFlash::blank());
+		let data = swiss_file(1, 0xAA);
+		fs.write(0, &data, WriteOptions::default(), &()).unwrap();
+
+		let mut reader = fs.reader(0).unwrap();
+		let mut read = Vec::new();
+		reader.read_to_end(&mut read).unwrap();
+		assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+		drop(reader);
+		assert_eq!(read, data);
+		// The mock rejects any stray read command, and the next operation must work
+		assert_eq!(fs.read(0, &()).unwrap(), data);
+	}
+
+	#[test]
+	fn stream_into_slot() {
+		use std::io::Write;
+
+		let mut fs = mount(MockFlash::blank());
+		let mut data = swiss_file(2, 0x33);
+		data.truncate(data.len() - 1000);
+		let size = u32::to_be_bytes(data.len() as _);
+		data[0xFC..=0xFF].copy_from_slice(&size);
+
+		let mut writer = fs.writer(4, data.len()).unwrap();
+		for chunk in data.chunks(100) {
+			writer.write_all(chunk).unwrap();
+		}
+		writer.finish().unwrap();
+
+		assert_eq!(fs.occupied_sectors(4), Some(2));
+		assert_eq!(
+			fs.slot_info(4).unwrap().size(),
+			2 * device::SECTOR_SIZE,
+			"size not padded"
+		);
+		assert_eq!(
+			fs.read_file(4, &()).unwrap()[..data.len() - HEADER_SIZE],
+			data[HEADER_SIZE..]
+		);
+	}
+
+	#[test]
+	fn stream_rejects_bad_header() {
+		use std::io::Write;
+
+		let mut fs = mount(MockFlash::blank());
+		let mut junk = swiss_file(2, 0x33);
+		junk[0..4].copy_from_slice(b"JUNK");
+		let mut short = swiss_file(2, 0x33);
+		short[0xFC..=0xFF].copy_from_slice(&u32::to_be_bytes(device::SECTOR_SIZE as _));
+
+		for data in [junk, short] {
+			let mut writer = fs.writer(4, data.len()).unwrap();
+			assert!(writer.write_all(&data).is_err());
+			drop(writer);
+			assert!(fs.iter_slots().all(|s| matches!(s, SectorOccupancy::Empty)));
+		}
+
+		// Nothing was written, so the space can be used as is
+		let data = swiss_file(2, 0x44);
+		fs.write(4, &data, WriteOptions::default(), &()).unwrap();
+		assert_eq!(fs.read(4, &()).unwrap(), data);
+	}
+
+	#[test]
+	fn swap_equal_sizes() {
+		let mut fs = mount(MockFlash::blank());
+		let a = swiss_file(2, 0xAA);
+		let b = swiss_file(2, 0xBB);
+		fs.write(2, &a, WriteOptions::default(), &()).unwrap();
+		fs.write(6, &b, WriteOptions::default(), &()).unwrap();
+
+		fs.swap_files(2, 6, &()).unwrap();
+		fs.scan(&()).unwrap();
+		assert_eq!(fs.read(2, &()).unwrap(), b);
+		assert_eq!(fs.read(6, &()).unwrap(), a);
+		assert_eq!(fs.occupied_sectors(2), Some(2));
+		assert_eq!(fs.occupied_sectors(6), Some(2));
+	}
+
+	#[test]
+	fn swap_unequal_sizes() {
+		let mut fs = mount(MockFlash::blank());
+		let a = swiss_file(1, 0xAA);
+		let b = swiss_file(3, 0xBB);
+		fs.write(0, &a, WriteOptions::default(), &()).unwrap();
+		fs.write(4, &b, WriteOptions::default(), &()).unwrap();
+
+		fs.swap_files(0, 4, &()).unwrap();
+		fs.scan(&()).unwrap();
+		assert_eq!(fs.read(0, &()).unwrap(), b);
+		assert_eq!(fs.read(4, &()).unwrap(), a);
+		assert_eq!(fs.occupied_sectors(0), Some(3));
+		assert_eq!(fs.occupied_sectors(4), Some(1));
+		assert!(fs.verify_empty(5..7, &()).unwrap());
+		assert!(fs.verify_empty(3..4, &()).unwrap());
+	}
+
+	#[test]
+	fn swap_overlapping() {
+		let mut fs = mount(MockFlash::blank());
+		let a = swiss_file(1, 0xAA);
+		let b = swiss_file(3, 0xBB);
+		fs.write(0, &a, WriteOptions::default(), &()).unwrap();
+		fs.write(1, &b, WriteOptions::default(), &()).unwrap();
+
+		assert!(matches!(
+			fs.swap_files(0, 1, &()),
+			Err(QoobError::SwapOverlap)
+		));
+		assert_eq!(fs.read(0, &()).unwrap(), a);
+		assert_eq!(fs.read(1, &()).unwrap(), b);
+	}
+
+	#[test]
+	fn manifest_keeps_latin1_description() {
+		let mut source = mount(MockFlash::blank());
+		let mut data = swiss_file(1, 0x11);
+		data[0x04..0x0C].copy_from_slice(b"Caf\xe9 \xe0 1");
+		source
+			.write(3, &data, WriteOptions::default(), &())
+			.unwrap();
+		let file = source.read_sized(3, &()).unwrap();
+
+		let manifest = Manifest::parse(&source.export_manifest().to_string()).unwrap();
+		let mut dest = mount(MockFlash::blank());
+		dest.apply_manifest(
+			&manifest,
+			|_| Ok(file.clone()),
+			WriteOptions::default(),
+			&(),
+		)
+		.unwrap();
+		assert_eq!(
+			dest.slot_info(3).unwrap().description(),
+			source.slot_info(3).unwrap().description()
+		);
+	}
+
+	#[test]
+	fn manifest_rejects_slot_past_the_end() {
+		let manifest = Manifest::parse(&format!(
+			"[[file]]\nslot = {}\ntype = \"Swiss\"\npath = \"a.bin\"\n",
+			u64::MAX
+		))
+		.unwrap();
+		let mut fs = mount(MockFlash::blank());
+		let result = fs.apply_manifest(
+			&manifest,
+			|_| panic!("resolved a file for an impossible slot"),
+			WriteOptions::default(),
+			&(),
+		);
+		assert!(matches!(
+			result,
+			Err(QoobError::ManifestSlot { error, .. })
+				if matches!(*error, QoobError::OutOfBounds { .. })
+		));
+	}
+
+	#[test]
+	fn handles_are_send() {
+		fn is_send<T: Send>() {}
+		is_send::<QoobDevice>();
+		is_send::<QoobFs>();
+	}
+}
@@ -1,8 +1,11 @@
 pub mod device;
 pub mod error;
+pub mod formats;
 pub mod fs;
+pub mod manifest;
+pub mod transport;
 pub mod util;
 
-pub use device::QoobDevice;
+pub use device::{BusGuard, QoobDevice};
 pub use error::{QoobError, QoobResult};
 pub use fs::QoobFs;
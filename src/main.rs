@@ -1,12 +1,15 @@
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use clap::{CommandFactory, Parser, Subcommand};
 
 use rqoob::device;
 use rqoob::fs;
+use rqoob::transport::Transport;
 use rqoob::util::{ProgressBar, ProgressBarFactory};
 use rqoob::QoobDevice;
 use rqoob::QoobError;
@@ -15,6 +18,41 @@ use rqoob::QoobFs;
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+	/// Print results and errors as JSON
+	#[arg(long, global = true)]
+	json: bool,
+	/// Split bulk transfers into chunks of this many bytes
+	#[arg(long, global = true, value_parser = 1..=device::MAX_TRANSFER_SIZE as i64)]
+	transfer_size: Option<i64>,
+	/// Match devices with this USB vendor ID (hex) instead of the Qoob's
+	#[arg(long, global = true, value_parser = parse_usb_id)]
+	vid: Option<u16>,
+	/// Match devices with this USB product ID (hex) instead of the Qoob's
+	#[arg(long, global = true, value_parser = parse_usb_id)]
+	pid: Option<u16>,
+	/// Match devices with this USB manufacturer string instead of the Qoob's
+	#[arg(long, global = true)]
+	manufacturer: Option<String>,
+	/// Match devices with this USB product string instead of the Qoob's
+	#[arg(long, global = true)]
+	product_string: Option<String>,
+	/// Wait up to this many seconds for a device to be connected
+	#[arg(long, global = true, value_name = "SECS")]
+	wait: Option<u64>,
+	/// Don't ask for confirmation before erasing data
+	#[arg(short, long, global = true)]
+	yes: bool,
+	/// Don't show progress bars, implied when stdout isn't a terminal
+	#[arg(short, long, global = true)]
+	quiet: bool,
+	/// Log USB reports to stderr, give twice to dump them in full
+	#[arg(short, long, global = true, action = clap::ArgAction::Count)]
+	verbose: u8,
+	/// Check that the flash chip is the expected size before doing anything (experimental)
+	///
+	/// Assumes reads past the end of flash wrap around, which is unconfirmed on hardware.
+	#[arg(long, global = true)]
+	check_geometry: bool,
 	#[command(subcommand)]
 	command: Commands,
 }
@@ -22,7 +60,11 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
 	/// List flash contents
-	List,
+	List {
+		/// Read BIOS files to tell the stock Qoob BIOS apart from others
+		#[arg(long)]
+		identify: bool,
+	},
 	/// Dump a file from flash
 	Read {
 		/// The slot to read from
@@ -30,6 +72,19 @@ enum Commands {
 		slot: i64,
 		/// The destination file
 		file: PathBuf,
+		/// Only save the file's contents, without its header or padding
+		///
+		/// BIOS images are saved whole, as their header is part of the image.
+		#[arg(long)]
+		strip_header: bool,
+	},
+	/// Show everything in a slot's header
+	///
+	/// The header is shown even if it isn't recognized.
+	Info {
+		/// The slot to inspect
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		slot: i64,
 	},
 	/// Remove a file from flash
 	Remove {
@@ -47,43 +102,209 @@ enum Commands {
 		/// Overwrite an existing file in the slot
 		#[arg(long)]
 		overwrite: bool,
+		/// Only print what would be written and erased
+		#[arg(long)]
+		dry_run: bool,
+		/// Finish an interrupted write of the same file, starting at this sector
+		#[arg(
+			long,
+			value_name = "SECTOR",
+			conflicts_with = "overwrite",
+			value_parser = 0..=device::SECTOR_COUNT as i64 - 1
+		)]
+		resume_from: Option<i64>,
 		/// Read back the written data and check that it matches
+		#[arg(
+			long,
+			value_name = "MODE",
+			num_args = 0..=1,
+			default_value = "off",
+			default_missing_value = "readback"
+		)]
+		verify: VerifyArg,
+		/// Keep the file's exact size in its header instead of rounding it up to whole sectors
+		#[arg(long)]
+		exact_size: bool,
+		/// Refuse files whose header doesn't declare exactly their length, e.g. truncated ones
+		#[arg(long)]
+		strict_size: bool,
+		/// The file has no Qoob header: detect its type and generate one
+		///
+		/// ELF and DOL files are stored as ELF, anything else as a Swiss file.
+		/// The file name becomes the description.
+		#[arg(long)]
+		raw: bool,
+		/// The type to store a raw file as, instead of detecting it (see `types`)
+		#[arg(long = "type", value_name = "TYPE", requires = "raw")]
+		file_type: Option<fs::FileType>,
+	},
+	/// Dump the whole flash, only reading sectors that aren't empty
+	///
+	/// Empty sectors are filled in with 0xFF.
+	Dump {
+		/// The destination file
+		file: PathBuf,
+	},
+	/// Compare a file with the contents of a slot
+	///
+	/// Exits with a nonzero status if they differ.
+	Verify {
+		/// The slot to check
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		slot: i64,
+		/// The file it should contain, as passed to write
+		file: PathBuf,
+	},
+	/// Compare a full flash image with the contents of flash
+	///
+	/// Exits with a nonzero status if they differ.
+	VerifyImage {
+		/// The image, as produced by dump or raw read all
+		file: PathBuf,
+	},
+	/// Change the description of a file
+	Rename {
+		/// The slot the file is in
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		slot: i64,
+		/// The new description
+		description: String,
+	},
+	/// Copy a file to another slot
+	Copy {
+		/// The slot the file is in
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		from: i64,
+		/// The slot to copy it to
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		to: i64,
+		/// Read back the written data and check that it matches
+		#[arg(
+			long,
+			value_name = "MODE",
+			num_args = 0..=1,
+			default_value = "off",
+			default_missing_value = "readback"
+		)]
+		verify: VerifyArg,
+	},
+	/// Make two files trade places
+	Swap {
+		/// The slot of the first file
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		a: i64,
+		/// The slot of the second file
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		b: i64,
+	},
+	/// Move a file to another slot
+	Move {
+		/// The slot the file is in
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		from: i64,
+		/// The slot to move it to
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		to: i64,
+	},
+	/// Pack files towards the start of flash
+	Defrag {
+		/// Only print the planned moves
 		#[arg(long)]
-		verify: bool,
+		dry_run: bool,
+	},
+	/// Check that the flash chip works, using a blank sector as scratch space
+	Selftest {
+		/// The sector to test, which must be blank
+		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
+		sector: i64,
+	},
+	/// Save the flash layout or apply a saved one
+	Manifest {
+		#[command(subcommand)]
+		command: ManifestCommands,
 	},
 	/// Operate on raw flash sectors
 	Raw {
 		#[command(subcommand)]
 		command: RawCommands,
 	},
+	/// List known file types
+	Types,
 	/// Generate shell completions
+	#[command(alias = "completions")]
 	GenCompletions {
 		/// The shell to generate completions for
 		shell: clap_complete::Shell,
 	},
 }
 
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum VerifyArg {
+	/// Don't verify
+	Off,
+	/// Compare each chunk byte for byte right after writing it, stopping at the first mismatch
+	Readback,
+	/// Compare CRC-32 checksums, without keeping a second copy in memory
+	Checksum,
+	/// Only check that the device reports no problem, without reading anything back
+	Status,
+}
+
+impl From<VerifyArg> for fs::VerifyMode {
+	fn from(arg: VerifyArg) -> Self {
+		match arg {
+			VerifyArg::Off => Self::Off,
+			VerifyArg::Readback => Self::Readback,
+			VerifyArg::Checksum => Self::Checksum,
+			VerifyArg::Status => Self::StatusOnly,
+		}
+	}
+}
+
+#[derive(Subcommand)]
+enum ManifestCommands {
+	/// Describe which file is in which slot
+	Export {
+		/// The manifest to create
+		file: PathBuf,
+		/// Also save every file next to the manifest, under the path it lists
+		#[arg(long)]
+		with_files: bool,
+	},
+	/// Write the files listed in a manifest, checking them all first
+	Apply {
+		/// The manifest to apply
+		file: PathBuf,
+		/// Read back the written data and check that it matches
+		#[arg(
+			long,
+			value_name = "MODE",
+			num_args = 0..=1,
+			default_value = "off",
+			default_missing_value = "readback"
+		)]
+		verify: VerifyArg,
+	},
+}
+
 #[derive(Subcommand)]
 enum RawCommands {
 	/// Dump sectors
 	Read {
-		/// The first sector to dump
-		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
-		start: i64,
-		/// The last sector to dump (inclusive)
-		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
-		end: i64,
+		/// The sectors to dump: N, N-M (inclusive) or all
+		#[arg(value_parser = parse_sector_range)]
+		sectors: std::ops::Range<usize>,
 		/// The destination file
 		file: PathBuf,
 	},
 	/// Erase sectors
 	Erase {
-		/// The first sector to erase
-		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
-		start: i64,
-		/// The last sector to erase (inclusive)
-		#[arg(value_parser = 0..=device::SECTOR_COUNT as i64 - 1)]
-		end: i64,
+		/// The sectors to erase: N, N-M (inclusive) or all
+		#[arg(value_parser = parse_sector_range)]
+		sectors: std::ops::Range<usize>,
+		/// Erase sectors even if they're already blank
+		#[arg(long)]
+		force: bool,
 	},
 	/// Write sectors (does not pre-erase)
 	Write {
@@ -95,7 +316,38 @@ enum RawCommands {
 	},
 }
 
-struct IndicatifProgressBarFactory;
+/// Parse a range of sectors given as `N`, `N-M` (inclusive) or `all`
+fn parse_sector_range(s: &str) -> Result<std::ops::Range<usize>, String> {
+	if s == "all" {
+		return Ok(0..device::SECTOR_COUNT);
+	}
+	let sector = |n: &str| match n.parse() {
+		Ok(n) if n < device::SECTOR_COUNT => Ok(n),
+		Ok(n) => Err(format!(
+			"sector {n} is not in 0-{}",
+			device::SECTOR_COUNT - 1
+		)),
+		Err(e) => Err(format!("{e}")),
+	};
+	let (start, end) = match s.split_once('-') {
+		Some((start, end)) => (sector(start)?, sector(end)?),
+		None => (sector(s)?, sector(s)?),
+	};
+	if end < start {
+		return Err(format!("{s} ends before it starts"));
+	}
+	Ok(start..end + 1)
+}
+
+/// Parse a hexadecimal USB vendor or product ID
+fn parse_usb_id(s: &str) -> Result<u16, std::num::ParseIntError> {
+	u16::from_str_radix(s.trim_start_matches("0x"), 16)
+}
+
+struct IndicatifProgressBarFactory {
+	/// Create bars that are never drawn
+	hidden: bool,
+}
 
 impl ProgressBarFactory for IndicatifProgressBarFactory {
 	type BarType = IndicatifProgressBar;
@@ -107,7 +359,12 @@ impl ProgressBarFactory for IndicatifProgressBarFactory {
 			format!("{} {}", bar, "{bytes}/{total_bytes}")
 		};
 
-		let pb = indicatif::ProgressBar::new(len as u64)
+		let pb = if self.hidden {
+			indicatif::ProgressBar::hidden()
+		} else {
+			indicatif::ProgressBar::new(len as u64)
+		};
+		let pb = pb
 			.with_style(
 				indicatif::ProgressStyle::with_template(&template)
 					.unwrap()
@@ -115,42 +372,393 @@ impl ProgressBarFactory for IndicatifProgressBarFactory {
 			)
 			.with_message(msg);
 		pb.tick();
-		IndicatifProgressBar(pb)
+		IndicatifProgressBar { pb, template }
 	}
 }
 
-struct IndicatifProgressBar(indicatif::ProgressBar);
+/// Prints every report going through a transport to stderr
+struct LoggingTransport {
+	inner: Box<dyn Transport>,
+	/// 1 to print the start of each report, 2 or more for the whole thing
+	verbosity: u8,
+}
+
+impl LoggingTransport {
+	fn log(&self, direction: &str, buf: &[u8], result: &rqoob::QoobResult<usize>) {
+		let shown = if self.verbosity > 1 {
+			buf
+		} else {
+			&buf[..buf.len().min(8)]
+		};
+		let ellipsis = if shown.len() < buf.len() { " ..." } else { "" };
+		match result {
+			Ok(n) => eprintln!("{direction} {}{ellipsis} ({n} bytes)", hex(shown)),
+			Err(e) => eprintln!("{direction} {}{ellipsis} failed: {e}", hex(shown)),
+		}
+	}
+}
+
+impl Transport for LoggingTransport {
+	fn write(&self, buf: &[u8]) -> rqoob::QoobResult<usize> {
+		let result = self.inner.write(buf);
+		self.log(">", buf, &result);
+		result
+	}
+
+	fn get_feature_report(&self, buf: &mut [u8]) -> rqoob::QoobResult<usize> {
+		let result = self.inner.get_feature_report(buf);
+		self.log("<", buf, &result);
+		result
+	}
+}
+
+struct IndicatifProgressBar {
+	pb: indicatif::ProgressBar,
+	template: String,
+}
 
 impl ProgressBar for IndicatifProgressBar {
 	fn inc(&self, n: usize) {
-		self.0.inc(n as u64);
+		self.pb.inc(n as u64);
 	}
 	fn set(&self, n: usize) {
-		self.0.set_position(n as u64);
+		self.pb.set_position(n as u64);
 	}
 	fn finish(&self) {
-		self.0.finish();
+		self.pb.finish();
+	}
+	fn abandon(&self, msg: &str) {
+		let template = self
+			.template
+			.replace("{msg:10}", "{msg:10.red}")
+			.replace("{bar:40}", "{bar:40.red}");
+		self.pb.set_style(
+			indicatif::ProgressStyle::with_template(&template)
+				.unwrap()
+				.progress_chars("##-"),
+		);
+		self.pb.abandon_with_message(msg.to_owned());
+	}
+}
+
+/// Quote and escape a string for JSON output
+fn json_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+/// Escape arbitrary bytes for display
+fn escape_bytes(bytes: &[u8]) -> String {
+	String::from_iter(
+		bytes
+			.iter()
+			.flat_map(|&b| std::ascii::escape_default(b))
+			.map(|b| b as char),
+	)
+}
+
+/// Render bytes as lowercase hex digits
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Render a list of JSON values as an array
+fn json_array<T: std::fmt::Display>(items: impl IntoIterator<Item = T>) -> String {
+	let items: Vec<_> = items.into_iter().map(|i| i.to_string()).collect();
+	format!("[{}]", items.join(","))
+}
+
+/// A JSON object, built field by field
+#[derive(Default)]
+struct JsonObject(Vec<(&'static str, String)>);
+
+impl JsonObject {
+	/// Add a string field
+	fn string(mut self, key: &'static str, value: &str) -> Self {
+		self.0.push((key, json_string(value)));
+		self
+	}
+
+	/// Add a field that is already valid JSON (numbers, booleans, nested values)
+	fn raw(mut self, key: &'static str, value: impl std::fmt::Display) -> Self {
+		self.0.push((key, value.to_string()));
+		self
+	}
+}
+
+impl std::fmt::Display for JsonObject {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{{")?;
+		for (i, (key, value)) in self.0.iter().enumerate() {
+			if i != 0 {
+				write!(f, ",")?;
+			}
+			write!(f, "{}:{}", json_string(key), value)?;
+		}
+		write!(f, "}}")
+	}
+}
+
+/// Render an error as a JSON object
+fn error_json(error: &(dyn Error + 'static)) -> JsonObject {
+	let kind = match error.downcast_ref::<QoobError>() {
+		Some(e) => e.kind(),
+		None if error.is::<std::io::Error>() => "Io",
+		None => "Other",
+	};
+	let error = JsonObject::default()
+		.string("kind", kind)
+		.string("message", &error.to_string());
+	JsonObject::default().raw("error", error)
+}
+
+/// Ask before destroying data, unless told not to or there's nobody to ask
+///
+/// `summary` describes what will be lost.
+fn confirm(yes: bool, summary: impl FnOnce() -> String) -> Result<(), Box<dyn Error>> {
+	if yes || !std::io::stdin().is_terminal() {
+		return Ok(());
+	}
+	eprint!("{}\nContinue? [y/N] ", summary());
+	// Our handler doesn't interrupt the read, so let Ctrl-C quit as usual while waiting
+	let handler = unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+	let mut answer = String::new();
+	let read = std::io::stdin().read_line(&mut answer);
+	unsafe {
+		libc::signal(libc::SIGINT, handler);
+	}
+	read?;
+	match answer.trim() {
+		"y" | "Y" | "yes" => Ok(()),
+		_ => Err("Aborted".into()),
+	}
+}
+
+/// Create a file through a temporary one, renamed into place once `write` succeeds
+///
+/// If anything goes wrong, the temporary file is removed and an existing file is left untouched.
+fn write_atomically(
+	path: &Path,
+	write: impl FnOnce(&mut File) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+	let name = path
+		.file_name()
+		.ok_or("The destination is not a file name")?;
+	let mut tmp_name = std::ffi::OsString::from(".");
+	tmp_name.push(name);
+	tmp_name.push(".tmp");
+	let tmp = path.with_file_name(tmp_name);
+
+	let mut file = File::create(&tmp)?;
+	let result = write(&mut file).and_then(|()| Ok(file.sync_all()?));
+	drop(file);
+	match result {
+		Ok(()) => Ok(std::fs::rename(&tmp, path)?),
+		Err(e) => {
+			let _ = std::fs::remove_file(&tmp);
+			Err(e)
+		}
+	}
+}
+
+/// Describe what erasing a range of sectors would destroy
+fn erase_summary(fs: &QoobFs, range: std::ops::Range<usize>) -> String {
+	let mut summary = format!(
+		"This will erase sectors {}..={}",
+		range.start,
+		range.end - 1
+	);
+	for (slot, header) in fs.iter_files() {
+		if slot < range.end && slot + header.sector_count(fs.geometry()) > range.start {
+			summary += &format!(
+				"\n  slot {slot}: {} \"{}\" ({} sectors)",
+				header.r#type().str(),
+				header.description_lossy(),
+				header.sector_count(fs.geometry()),
+			);
+		}
+	}
+	for (i, sector) in fs.iter_slots().enumerate() {
+		if range.contains(&i) && matches!(sector, fs::SectorOccupancy::Unknown) {
+			summary += &format!("\n  sector {i}: unrecognized data");
+		}
+	}
+	summary
+}
+
+/// Describe what a write would do
+fn print_write_plan(json: bool, fs: &QoobFs, plan: &fs::WritePlan) {
+	let status = match plan.check {
+		fs::RangeCheck::Empty => "empty",
+		fs::RangeCheck::Occupied => "occupied",
+		fs::RangeCheck::Overlap => "overlap",
+		fs::RangeCheck::Overflow => "overflow",
+	};
+	if json {
+		let plan = JsonObject::default()
+			.string("type", plan.header.r#type().str())
+			.string("description", &plan.header.description_lossy())
+			.raw("start", plan.range.start)
+			.raw("sector_count", plan.range.len())
+			.string("destination", status)
+			.raw("needs_erase", plan.needs_erase);
+		println!("{plan}");
+		return;
+	}
+
+	println!(
+		"{} \"{}\" would span sectors {}..={}",
+		plan.header.r#type().str(),
+		plan.header.description_lossy(),
+		plan.range.start,
+		plan.range.end - 1,
+	);
+	match plan.check {
+		fs::RangeCheck::Empty => println!("The destination is blank"),
+		fs::RangeCheck::Occupied => {
+			println!("The destination must be erased first (--overwrite)");
+			println!("{}", erase_summary(fs, plan.range.clone()));
+		}
+		fs::RangeCheck::Overlap => println!("The destination overlaps another file"),
+		fs::RangeCheck::Overflow => println!("The file doesn't fit in flash at this slot"),
+	}
+}
+
+/// Print the outcome of a comparison, exiting with an error status on mismatch
+fn report_comparison(json: bool, first_difference: Option<usize>) {
+	if json {
+		let result = JsonObject::default().raw("matches", first_difference.is_none());
+		let result = match first_difference {
+			Some(offset) => result.raw("first_difference", offset),
+			None => result,
+		};
+		println!("{result}");
+	} else {
+		match first_difference {
+			Some(offset) => println!("Mismatch at offset {offset:#x}"),
+			None => println!("Contents match"),
+		}
+	}
+	if first_difference.is_some() {
+		std::process::exit(1);
+	}
+}
+
+/// Set when Ctrl-C is pressed, so the current operation can stop cleanly
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+	INTERRUPTED.store(true, Ordering::Relaxed);
+	// Let a second Ctrl-C kill the process if cancelling gets stuck
+	unsafe {
+		libc::signal(libc::SIGINT, libc::SIG_DFL);
+	}
+}
+
+fn install_interrupt_handler() {
+	let handler = on_interrupt as extern "C" fn(libc::c_int);
+	unsafe {
+		libc::signal(libc::SIGINT, handler as libc::sighandler_t);
 	}
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
 	let cli = Cli::parse();
+	let json = cli.json;
 
-	if let Commands::GenCompletions { shell } = cli.command {
-		let mut cmd = Cli::command();
-		let name = cmd.get_name().to_string();
-		clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
-		return Ok(());
+	match run(cli) {
+		Err(e) if json => {
+			println!("{}", error_json(&*e));
+			std::process::exit(1);
+		}
+		result => result,
 	}
+}
 
-	let qoob = QoobDevice::connect()?;
-	let pbf = IndicatifProgressBarFactory;
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+	// Commands that don't need a device
+	match cli.command {
+		Commands::GenCompletions { shell } => {
+			let mut cmd = Cli::command();
+			let name = cmd.get_name().to_string();
+			clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+			return Ok(());
+		}
+		Commands::Types => {
+			if cli.json {
+				let types = fs::FileType::all().iter().map(|t| {
+					JsonObject::default()
+						.string("type", t.str())
+						.string("magic", &escape_bytes(&t.magic()))
+						.raw("bootable", t.is_bootable())
+						.raw("wrappable", t.is_wrappable())
+				});
+				println!("{}", json_array(types));
+			} else {
+				println!("Type  Magic    Bootable Wrappable");
+				for t in fs::FileType::all() {
+					let magic = escape_bytes(&t.magic());
+					let bootable = if t.is_bootable() { "yes" } else { "no" };
+					let wrappable = if t.is_wrappable() { "yes" } else { "no" };
+					println!("{:<5} {magic:<8} {bootable:<8} {wrappable}", t.str());
+				}
+			}
+			return Ok(());
+		}
+		_ => {}
+	}
+
+	let mut filter = device::DeviceFilter::default();
+	if cli.vid.is_some() {
+		filter.vendor_id = cli.vid;
+	}
+	if cli.pid.is_some() {
+		filter.product_id = cli.pid;
+	}
+	if cli.manufacturer.is_some() {
+		filter.manufacturer = cli.manufacturer;
+	}
+	if cli.product_string.is_some() {
+		filter.product = cli.product_string;
+	}
+	let qoob = match cli.wait {
+		Some(secs) => QoobDevice::connect_with_filter_wait(filter, Duration::from_secs(secs))?,
+		None => QoobDevice::connect_with_filter(filter)?,
+	};
+	let mut qoob = match cli.verbose {
+		0 => qoob,
+		verbosity => qoob.map_transport(|inner| Box::new(LoggingTransport { inner, verbosity })),
+	};
+	if let Some(size) = cli.transfer_size {
+		qoob.set_transfer_size(size as usize);
+	}
+	if cli.check_geometry {
+		qoob.check_geometry()?;
+	}
+	install_interrupt_handler();
+	qoob.set_cancel_check(Box::new(|| INTERRUPTED.load(Ordering::Relaxed)));
+	let pbf = IndicatifProgressBarFactory {
+		hidden: cli.quiet || !std::io::stdout().is_terminal(),
+	};
 
 	match cli.command {
-		Commands::List => {
+		Commands::List { identify } => {
 			let fs = QoobFs::from_device(qoob, &pbf)?;
 
-			println!("Slot Blocks Type  Description");
+			let mut entries = Vec::new();
 			for (i, &slot) in fs.iter_slots().enumerate() {
 				let info = fs.slot_info(i);
 				let (r#type, blocks, desc) = match slot {
@@ -158,72 +766,374 @@ fn main() -> Result<(), Box<dyn Error>> {
 						let info = info.unwrap();
 						(
 							info.r#type().str(),
-							info.sector_count(),
-							info.description_string(),
+							info.sector_count(fs.geometry()),
+							info.description_lossy(),
 						)
 					}
 					fs::SectorOccupancy::Slot(_) => continue,
-					fs::SectorOccupancy::Unknown => ("???", 1, String::from("Unknown")),
+					fs::SectorOccupancy::Unknown => {
+						let magic = u32::from_be_bytes(fs.raw_header(i)?.magic());
+						("???", 1, format!("Unknown (magic {magic:#010x})"))
+					}
 					fs::SectorOccupancy::Empty => continue,
 				};
-				println!("{i:>4} {blocks:>6} {type:<5} {desc}");
+				let bios = if identify && matches!(slot, fs::SectorOccupancy::Slot(_)) {
+					fs.bios_kind(i, &pbf)?
+				} else {
+					None
+				};
+				entries.push((i, blocks, r#type, desc, bios));
 			}
+
+			if cli.json {
+				let entries = entries.iter().map(|(slot, blocks, r#type, desc, bios)| {
+					let entry = JsonObject::default()
+						.raw("slot", slot)
+						.raw("sector_count", blocks)
+						.string("type", r#type)
+						.string("description", desc);
+					match bios {
+						Some(kind) => entry.string("bios", kind.str()),
+						None => entry,
+					}
+				});
+				println!("{}", json_array(entries));
+			} else {
+				println!("Slot Blocks Type  Description");
+				for (i, blocks, r#type, desc, bios) in entries {
+					match bios {
+						Some(kind) => {
+							println!("{i:>4} {blocks:>6} {type:<5} {desc} ({})", kind.str())
+						}
+						None => println!("{i:>4} {blocks:>6} {type:<5} {desc}"),
+					}
+				}
+				let usage = fs.usage();
+				println!(
+					"{} sectors used, {} free, {} unrecognized",
+					usage.used_sectors, usage.free_sectors, usage.unknown_sectors,
+				);
+				for conflict in fs.scan_report().conflicts {
+					eprintln!(
+						"Warning: sector {} holds a header but lies within the file in slot {}",
+						conflict.sector, conflict.slot,
+					);
+				}
+			}
+		}
+		Commands::Read {
+			slot,
+			file,
+			strip_header,
+		} => {
+			let slot = slot as usize;
+			// Fail before touching the device if the destination is unusable
+			write_atomically(&file, |file| {
+				let fs = QoobFs::from_device(qoob, &pbf)?;
+				fs.slot_info(slot)?;
+				let data = if strip_header {
+					fs.read_file(slot, &pbf)?
+				} else {
+					fs.read(slot, &pbf)?
+				};
+				Ok(file.write_all(&data)?)
+			})?;
 		}
-		Commands::Read { slot, file } => {
+		Commands::Info { slot } => {
 			let slot = slot as usize;
 			let fs = QoobFs::from_device(qoob, &pbf)?;
-			let data = fs.read(slot, &pbf)?;
-			let mut file = File::create(file)?;
-			file.write_all(&data)?;
+			let header = fs.raw_header(slot)?;
+			let magic = header.magic();
+			let description: Vec<u8> = header
+				.description()
+				.iter()
+				.copied()
+				.take_while(|&b| b != 0)
+				.collect();
+			let offset = fs.geometry().sector_to_offset(slot);
+			let sectors = header.sector_count(fs.geometry());
+			if cli.json {
+				let info = JsonObject::default()
+					.raw("slot", slot)
+					.raw("offset", offset)
+					.string("type", header.r#type().str())
+					.string("magic", &hex(&magic))
+					.string("description", &header.description_lossy())
+					.string("description_hex", &hex(&description))
+					.raw("size", header.size())
+					.raw("sector_count", sectors);
+				println!("{info}");
+			} else {
+				println!("Slot:        {slot}");
+				println!("Offset:      {offset:#x}");
+				println!("Type:        {}", header.r#type().str());
+				println!(
+					"Magic:       \"{}\" ({})",
+					escape_bytes(&magic),
+					hex(&magic)
+				);
+				println!("Description: \"{}\"", header.description_lossy());
+				println!("             ({})", hex(&description));
+				println!("Size:        {} bytes", header.size());
+				println!("Sectors:     {sectors} ({slot}..{})", slot + sectors);
+			}
 		}
 		Commands::Remove { slot } => {
 			let slot = slot as usize;
 			let mut fs = QoobFs::from_device(qoob, &pbf)?;
+			let sectors = fs.slot_info(slot)?.sector_count(fs.geometry());
+			confirm(cli.yes, || erase_summary(&fs, slot..slot + sectors))?;
 			fs.remove(slot, &pbf)?;
 		}
 		Commands::Write {
 			slot,
 			file,
 			overwrite,
+			dry_run,
+			resume_from,
 			verify,
+			exact_size,
+			strict_size,
+			raw,
+			file_type,
 		} => {
 			let slot = slot as usize;
 			let mut fs = QoobFs::from_device(qoob, &pbf)?;
+			let name = file
+				.file_name()
+				.map(|name| name.to_string_lossy().into_owned())
+				.unwrap_or_default();
 			let file = File::open(file)?;
 			let mut data = Vec::new();
-			file.take(device::FLASH_SIZE as u64)
+			file.take(fs.geometry().flash_size() as u64)
 				.read_to_end(&mut data)?;
-			if overwrite
-				&& matches!(
-					fs.check_dest_range(slot..slot + device::size_to_sectors(data.len())),
-					fs::RangeCheck::Occupied,
-				) {
-				fs.remove(slot, &pbf)?;
+			if raw {
+				let header = fs::Header::builder()
+					.file_type(file_type.unwrap_or_else(|| fs::FileType::detect(&data)))
+					.description(&name)
+					.payload_len(data.len())
+					.build()?;
+				data.splice(0..0, header.as_bytes().iter().copied());
+			}
+			let plan = fs.plan_write(slot, &data)?;
+			if dry_run {
+				print_write_plan(cli.json, &fs, &plan);
+				return Ok(());
+			}
+			let dest_range = plan.range;
+			let clear = overwrite && plan.needs_erase;
+			if clear {
+				confirm(cli.yes, || erase_summary(&fs, dest_range.clone()))?;
+			}
+			let options = fs::WriteOptions {
+				verify: verify.into(),
+				// Keep the payload's exact length so it can be extracted as is
+				pad_size: !exact_size && !raw,
+				strict_size,
+				overwrite: clear,
+			};
+
+			if let Some(sector) = resume_from {
+				fs.resume_write(slot, &data, sector as usize, options, &pbf)?;
+				return Ok(());
+			}
+
+			// Show erasing, writing and reading back as a single bar, counted in bytes
+			let sector_size = fs.geometry().sector_size;
+			let mut total = data.len();
+			if matches!(options.verify, fs::VerifyMode::Checksum) {
+				total += data.len();
 			}
-			fs.write(slot, &data, verify, &pbf)?;
+			if clear {
+				total += dest_range.len() * sector_size;
+			}
+			let group = pbf
+				.create_group(total, "Writing", None)
+				.with_unit_size(sector_size);
+			fs.write(slot, &data, options, &group)?;
+			group.finish();
+		}
+		Commands::Dump { file } => {
+			write_atomically(&file, |file| {
+				let fs = QoobFs::from_device(qoob, &pbf)?;
+				Ok(file.write_all(&fs.dump_image(&pbf)?)?)
+			})?;
+		}
+		Commands::Verify { slot, file } => {
+			let slot = slot as usize;
+			let mut data = Vec::new();
+			File::open(file)?.read_to_end(&mut data)?;
+			let fs = QoobFs::from_device(qoob, &pbf)?;
+			let comparison = fs.compare_file(slot, &data, &pbf)?;
+			report_comparison(cli.json, comparison.first_difference);
+		}
+		Commands::VerifyImage { file } => {
+			let mut image = Vec::new();
+			File::open(file)?.read_to_end(&mut image)?;
+			let expected = qoob.geometry().flash_size();
+			if image.len() != expected {
+				Err(QoobError::ImageSize {
+					expected,
+					actual: image.len(),
+				})?;
+			}
+			let fs = QoobFs::from_device(qoob, &pbf)?;
+			let diffs = fs.diff_image(&image, &pbf)?;
+			if cli.json {
+				let sectors = diffs.iter().map(|d| {
+					JsonObject::default()
+						.raw("sector", d.sector)
+						.raw("first_difference", d.first_difference)
+				});
+				let result = JsonObject::default()
+					.raw("matches", diffs.is_empty())
+					.raw("differing_sectors", json_array(sectors));
+				println!("{result}");
+			} else if diffs.is_empty() {
+				println!("Contents match");
+			} else {
+				for d in &diffs {
+					println!(
+						"Sector {:>2}: first mismatch at {:#x}",
+						d.sector, d.first_difference
+					);
+				}
+				println!(
+					"{} of {} sectors differ",
+					diffs.len(),
+					fs.geometry().sector_count
+				);
+			}
+			if !diffs.is_empty() {
+				std::process::exit(1);
+			}
+		}
+		Commands::Rename { slot, description } => {
+			let mut fs = QoobFs::from_device(qoob, &pbf)?;
+			fs.set_description(slot as usize, &description, &pbf)?;
 		}
+		Commands::Copy { from, to, verify } => {
+			let mut fs = QoobFs::from_device(qoob, &pbf)?;
+			let options = fs::WriteOptions {
+				verify: verify.into(),
+				..Default::default()
+			};
+			fs.copy_file(from as usize, to as usize, options, &pbf)?;
+		}
+		Commands::Swap { a, b } => {
+			let mut fs = QoobFs::from_device(qoob, &pbf)?;
+			fs.swap_files(a as usize, b as usize, &pbf)?;
+		}
+		Commands::Move { from, to } => {
+			let mut fs = QoobFs::from_device(qoob, &pbf)?;
+			fs.move_file(from as usize, to as usize, &pbf)?;
+		}
+		Commands::Defrag { dry_run } => {
+			let mut fs = QoobFs::from_device(qoob, &pbf)?;
+			let moves = if dry_run {
+				fs.defrag_plan()
+			} else {
+				fs.defragment(&pbf)?
+			};
+			if cli.json {
+				let moves = moves.iter().map(|m| {
+					JsonObject::default()
+						.raw("from", m.from)
+						.raw("to", m.to)
+						.raw("sector_count", m.sectors)
+				});
+				println!("{}", json_array(moves));
+			} else if moves.is_empty() {
+				println!("Nothing to move");
+			} else {
+				for m in moves {
+					println!("{:>4} -> {:>4} ({} sectors)", m.from, m.to, m.sectors);
+				}
+			}
+		}
+		Commands::Selftest { sector } => {
+			let report = qoob.selftest(sector as usize, &pbf)?;
+			if cli.json {
+				let steps = report.steps.iter().map(|(step, result)| {
+					let entry = JsonObject::default()
+						.string("step", step.str())
+						.raw("ok", result.is_ok());
+					match result {
+						Ok(()) => entry,
+						Err(e) => entry
+							.string("kind", e.kind())
+							.string("message", &e.to_string()),
+					}
+				});
+				println!("{}", json_array(steps));
+			} else {
+				for (step, result) in &report.steps {
+					match result {
+						Ok(()) => println!("{:<8} ok", step.str()),
+						Err(e) => println!("{:<8} FAILED: {e}", step.str()),
+					}
+				}
+			}
+			if !report.passed() {
+				std::process::exit(1);
+			}
+		}
+		Commands::Manifest { command } => match command {
+			ManifestCommands::Export { file, with_files } => {
+				let fs = QoobFs::from_device(qoob, &pbf)?;
+				let manifest = fs.export_manifest();
+				if with_files {
+					let dir = file.parent().unwrap_or(std::path::Path::new(""));
+					for entry in &manifest.entries {
+						let data = fs.read_sized(entry.slot, &pbf)?;
+						File::create(dir.join(&entry.path))?.write_all(&data)?;
+					}
+				}
+				File::create(file)?.write_all(manifest.to_string().as_bytes())?;
+			}
+			ManifestCommands::Apply { file, verify } => {
+				let manifest = rqoob::manifest::Manifest::parse(&std::fs::read_to_string(&file)?)?;
+				let dir = file.parent().unwrap_or(std::path::Path::new(""));
+				let mut fs = QoobFs::from_device(qoob, &pbf)?;
+				let options = fs::WriteOptions {
+					verify: verify.into(),
+					pad_size: false,
+					..Default::default()
+				};
+				fs.apply_manifest(
+					&manifest,
+					|entry| Ok(std::fs::read(dir.join(&entry.path))?),
+					options,
+					&pbf,
+				)?;
+			}
+		},
 		Commands::Raw { command } => match command {
-			RawCommands::Read { start, end, file } => {
-				let start = start as usize;
-				let end = end as usize;
-				let size = if end >= start {
-					(end - start + 1) * device::SECTOR_SIZE
+			RawCommands::Read { sectors, file } => {
+				let offset = qoob.geometry().sector_to_offset(sectors.start);
+				let size = qoob.geometry().sector_to_offset(sectors.len());
+				write_atomically(&file, |file| {
+					Ok(qoob.read_to_writer(offset, size, file, &pbf)?)
+				})?;
+			}
+			RawCommands::Erase { sectors, force } => {
+				let qoob = if cli.yes || !std::io::stdin().is_terminal() {
+					qoob
 				} else {
-					0
+					let fs = QoobFs::from_device(qoob, &pbf)?;
+					confirm(false, || erase_summary(&fs, sectors.clone()))?;
+					fs.into_device()
 				};
-				let mut data = vec![0; size];
-				qoob.read(start * device::SECTOR_SIZE, &mut data, &pbf)?;
-				let mut file = File::create(file)?;
-				file.write_all(&data)?;
-			}
-			RawCommands::Erase { start, end } => {
-				let start = start as usize;
-				let end = end as usize;
-				qoob.erase(start..end + 1, &pbf)?;
+				if force {
+					qoob.erase(sectors, &pbf)?;
+				} else {
+					qoob.erase_if_dirty(sectors, &pbf)?;
+				}
 			}
 			RawCommands::Write { start, file } => {
 				let start = start as usize;
-				let avail = (device::SECTOR_COUNT - start) * device::SECTOR_SIZE;
+				let geometry = qoob.geometry();
+				let avail = geometry.sector_to_offset(geometry.sector_count.saturating_sub(start));
 				let mut file = File::open(file)?;
 				let size = file.metadata()?.len();
 				if size > avail as u64 {
@@ -231,10 +1141,10 @@ fn main() -> Result<(), Box<dyn Error>> {
 				}
 				let mut data = Vec::new();
 				file.read_to_end(&mut data)?;
-				qoob.write(start * device::SECTOR_SIZE, &data, &pbf)?;
+				qoob.write(geometry.sector_to_offset(start), &data, &pbf)?;
 			}
 		},
-		Commands::GenCompletions { .. } => {}
+		Commands::GenCompletions { .. } | Commands::Types => {}
 	};
 
 	Ok(())
@@ -0,0 +1,176 @@
+//! Flash layouts that can be saved and reapplied to another chip
+//!
+//! Manifests are stored as a small subset of TOML, one `[[file]]` table per slot:
+//!
+//! ```toml
+//! [[file]]
+//! slot = 0
+//! type = "BIOS"
+//! description = "Qoob Pro BIOS"
+//! path = "bios.bin"
+//! ```
+//!
+//! Only integers and basic strings are understood.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::fs::FileType;
+use crate::{QoobError, QoobResult};
+
+/// One file in a [`Manifest`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+	/// The slot the file goes in
+	pub slot: usize,
+	/// The type recorded in its header
+	pub file_type: FileType,
+	/// The description recorded in its header
+	pub description: String,
+	/// Where to find the file, header included, relative to the manifest
+	pub path: PathBuf,
+}
+
+/// A description of which file goes in which slot, see [`QoobFs::export_manifest`]
+///
+/// [`QoobFs::export_manifest`]: crate::QoobFs::export_manifest
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+	pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+	/// Parse a manifest
+	///
+	/// Fails with [`QoobError::InvalidManifest`] pointing at the first line that can't be
+	/// understood, or at the `[[file]]` line of an incomplete entry.
+	pub fn parse(text: &str) -> QoobResult<Self> {
+		#[derive(Default)]
+		struct Partial {
+			line: usize,
+			slot: Option<usize>,
+			file_type: Option<FileType>,
+			description: Option<String>,
+			path: Option<PathBuf>,
+		}
+
+		fn finish(partial: Partial) -> QoobResult<ManifestEntry> {
+			let line = partial.line;
+			let Partial {
+				slot: Some(slot),
+				file_type: Some(file_type),
+				description,
+				path: Some(path),
+				..
+			} = partial
+			else {
+				return Err(QoobError::InvalidManifest { line });
+			};
+			Ok(ManifestEntry {
+				slot,
+				file_type,
+				description: description.unwrap_or_default(),
+				path,
+			})
+		}
+
+		let mut manifest = Self::default();
+		let mut current: Option<Partial> = None;
+		for (i, line) in text.lines().enumerate() {
+			let line_no = i + 1;
+			let invalid = || QoobError::InvalidManifest { line: line_no };
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			if line == "[[file]]" {
+				if let Some(partial) = current.take() {
+					manifest.entries.push(finish(partial)?);
+				}
+				current = Some(Partial {
+					line: line_no,
+					..Default::default()
+				});
+				continue;
+			}
+
+			let partial = current.as_mut().ok_or_else(invalid)?;
+			let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+			let value = value.trim();
+			match key.trim() {
+				"slot" => partial.slot = Some(value.parse().map_err(|_| invalid())?),
+				"type" => {
+					let name = parse_string(value).ok_or_else(invalid)?;
+					partial.file_type = Some(name.parse().map_err(|_| invalid())?);
+				}
+				"description" => {
+					partial.description = Some(parse_string(value).ok_or_else(invalid)?);
+				}
+				"path" => partial.path = Some(parse_string(value).ok_or_else(invalid)?.into()),
+				_ => return Err(invalid()),
+			}
+		}
+		if let Some(partial) = current {
+			manifest.entries.push(finish(partial)?);
+		}
+		Ok(manifest)
+	}
+}
+
+impl fmt::Display for Manifest {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (i, entry) in self.entries.iter().enumerate() {
+			if i != 0 {
+				writeln!(f)?;
+			}
+			writeln!(f, "[[file]]")?;
+			writeln!(f, "slot = {}", entry.slot)?;
+			writeln!(f, "type = {}", quote(entry.file_type.str()))?;
+			writeln!(f, "description = {}", quote(&entry.description))?;
+			writeln!(f, "path = {}", quote(&entry.path.to_string_lossy()))?;
+		}
+		Ok(())
+	}
+}
+
+/// Render a TOML basic string
+fn quote(s: &str) -> String {
+	let mut out = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"' => out += "\\\"",
+			'\\' => out += "\\\\",
+			'\n' => out += "\\n",
+			'\t' => out += "\\t",
+			c if c.is_control() => out += &format!("\\u{:04X}", c as u32),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+/// Parse a TOML basic string, as written by [`quote`]
+fn parse_string(s: &str) -> Option<String> {
+	let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+	let mut out = String::new();
+	let mut chars = inner.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' => match chars.next()? {
+				'"' => out.push('"'),
+				'\\' => out.push('\\'),
+				'n' => out.push('\n'),
+				't' => out.push('\t'),
+				'u' => {
+					let code: String = chars.by_ref().take(4).collect();
+					out.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+				}
+				_ => return None,
+			},
+			'"' => return None,
+			c => out.push(c),
+		}
+	}
+	Some(out)
+}
@@ -0,0 +1,167 @@
+//! The link between [`QoobDevice`](crate::QoobDevice) and the hardware
+
+use crate::QoobResult;
+
+/// Something that can carry HID reports to and from a device
+///
+/// Implemented for [`hidapi::HidDevice`].
+/// Other implementations can stand in for the hardware, e.g. to simulate a device.
+/// They must be [`Send`] so that a [`QoobDevice`](crate::QoobDevice) can be moved to another
+/// thread.
+pub trait Transport: Send {
+	/// Send an output report, returning how many bytes were sent
+	fn write(&self, buf: &[u8]) -> QoobResult<usize>;
+	/// Fetch a feature report into `buf`, returning how many bytes were received
+	///
+	/// The first byte of `buf` is the report ID.
+	fn get_feature_report(&self, buf: &mut [u8]) -> QoobResult<usize>;
+}
+
+impl Transport for hidapi::HidDevice {
+	fn write(&self, buf: &[u8]) -> QoobResult<usize> {
+		Ok(hidapi::HidDevice::write(self, buf)?)
+	}
+
+	fn get_feature_report(&self, buf: &mut [u8]) -> QoobResult<usize> {
+		Ok(hidapi::HidDevice::get_feature_report(self, buf)?)
+	}
+}
+
+/// A simulated Qoob, for tests
+#[cfg(test)]
+pub(crate) mod mock {
+	use std::sync::Mutex;
+
+	use super::Transport;
+	use crate::device::{FLASH_SIZE, SECTOR_SIZE};
+	use crate::QoobResult;
+
+	/// What the next reports are part of
+	enum Pending {
+		/// A command, or a status query being answered
+		Command,
+		/// Data to be written, `len` bytes left at `offset`
+		Write { offset: usize, len: usize },
+		/// Data being read, `len` bytes left at `offset`
+		Read { offset: usize, len: usize },
+	}
+
+	struct State {
+		flash: Vec<u8>,
+		sector_size: usize,
+		bus_held: bool,
+		pending: Pending,
+	}
+
+	/// Answers commands like a Qoob Pro would, backed by an in-memory flash image
+	///
+	/// Like real flash, writing can only clear bits, so writing over data that wasn't erased
+	/// leaves a mix of both.
+	/// Accessing flash without holding the bus, an empty transfer,
+	/// or a command sent before the previous read has been fully received panics.
+	pub(crate) struct MockFlash(Mutex<State>);
+
+	impl MockFlash {
+		/// A device whose flash holds `image`, which must be [`FLASH_SIZE`] bytes long
+		pub(crate) fn new(image: Vec<u8>) -> Self {
+			assert_eq!(image.len(), FLASH_SIZE);
+			Self::with_sector_size(image, SECTOR_SIZE)
+		}
+
+		/// A device whose flash holds `image`, erased `sector_size` bytes at a time
+		pub(crate) fn with_sector_size(image: Vec<u8>, sector_size: usize) -> Self {
+			assert_eq!(image.len() % sector_size, 0);
+			Self(Mutex::new(State {
+				flash: image,
+				sector_size,
+				bus_held: false,
+				pending: Pending::Command,
+			}))
+		}
+
+		/// A device with blank flash
+		pub(crate) fn blank() -> Self {
+			Self::new(vec![0xFF; FLASH_SIZE])
+		}
+	}
+
+	impl Transport for MockFlash {
+		fn write(&self, buf: &[u8]) -> QoobResult<usize> {
+			let state = &mut *self.0.lock().unwrap();
+			if let Pending::Write { offset, len } = state.pending {
+				let chunk = len.min(buf.len() - 2);
+				for (i, &b) in buf[2..2 + chunk].iter().enumerate() {
+					let len = state.flash.len();
+					state.flash[(offset + i) % len] &= b;
+				}
+				state.pending = match len - chunk {
+					0 => Pending::Command,
+					len => Pending::Write {
+						offset: offset + chunk,
+						len,
+					},
+				};
+				return Ok(buf.len());
+			}
+
+			if let Pending::Read { len, .. } = state.pending {
+				panic!("command sent with {len} bytes of a read still pending");
+			}
+
+			let offset = (buf[2] as usize) << 16 | (buf[3] as usize) << 8 | buf[4] as usize;
+			let len = (buf[5] as usize) << 8 | buf[6] as usize;
+			match buf[1] {
+				// Status
+				5 => {}
+				// Bus
+				8 => state.bus_held = buf[3] == 1,
+				// Erase
+				2 => {
+					assert!(state.bus_held, "erase without the bus");
+					let start = buf[2] as usize * state.sector_size;
+					state.flash[start..start + state.sector_size].fill(0xFF);
+				}
+				// Write
+				3 => {
+					assert!(state.bus_held, "write without the bus");
+					assert_ne!(len, 0, "empty write");
+					state.pending = Pending::Write { offset, len };
+				}
+				// Read
+				4 => {
+					assert!(state.bus_held, "read without the bus");
+					assert_ne!(len, 0, "empty read");
+					state.pending = Pending::Read { offset, len };
+				}
+				cmd => panic!("unexpected command {cmd}"),
+			}
+			Ok(buf.len())
+		}
+
+		fn get_feature_report(&self, buf: &mut [u8]) -> QoobResult<usize> {
+			let state = &mut *self.0.lock().unwrap();
+			buf[1..].fill(0);
+			match state.pending {
+				Pending::Read { offset, len } => {
+					let chunk = len.min(buf.len() - 2);
+					for i in 0..chunk {
+						buf[2 + i] = state.flash[(offset + i) % state.flash.len()];
+					}
+					state.pending = match len - chunk {
+						0 => Pending::Command,
+						len => Pending::Read {
+							offset: offset + chunk,
+							len,
+						},
+					};
+				}
+				Pending::Command => {
+					// Nothing is ever still erasing, and the GameCube never wants the bus
+					buf[4] = if state.bus_held { 0 } else { 1 };
+				}
+				Pending::Write { .. } => panic!("status query in the middle of a write"),
+			}
+			Ok(buf.len())
+		}
+	}
+}
@@ -1,12 +1,51 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::QoobResult;
+
 pub trait ProgressBarFactory {
 	type BarType: ProgressBar;
 	fn create(&self, len: usize, msg: &'static str, unit: Option<&'static str>) -> Self::BarType;
+
+	/// Create a single bar for an operation made of several steps
+	///
+	/// See [`Group`].
+	fn create_group(
+		&self,
+		total: usize,
+		msg: &'static str,
+		unit: Option<&'static str>,
+	) -> Group<Self::BarType> {
+		Group {
+			bar: Rc::new(self.create(total, msg, unit)),
+			unit_size: 1,
+		}
+	}
 }
 
 pub trait ProgressBar {
 	fn inc(&self, n: usize);
 	fn set(&self, n: usize);
 	fn finish(&self);
+	/// Leave the bar showing that the operation failed
+	///
+	/// Just finishes the bar by default.
+	fn abandon(&self, _msg: &str) {
+		self.finish();
+	}
+}
+
+/// Run `op`, then finish `pb` if it succeeded or abandon it if it failed
+pub(crate) fn track<P: ProgressBar, T>(
+	pb: P,
+	op: impl FnOnce(&P) -> QoobResult<T>,
+) -> QoobResult<T> {
+	let result = op(&pb);
+	match &result {
+		Ok(_) => pb.finish(),
+		Err(_) => pb.abandon("Failed"),
+	}
+	result
 }
 
 impl ProgressBarFactory for () {
@@ -24,4 +63,121 @@ impl ProgressBar for () {
 	fn inc(&self, _n: usize) {}
 	fn set(&self, _n: usize) {}
 	fn finish(&self) {}
+	fn abandon(&self, _msg: &str) {}
+}
+
+/// A bar shared by every step of an operation
+///
+/// Used as a factory, it hands out bars that all advance the shared bar,
+/// ignoring their own length and message.
+/// `total` should therefore be the sum of the lengths of all the steps,
+/// in the group's unit: steps that count something else, like sectors,
+/// are converted with [`Group::with_unit_size`].
+pub struct Group<B: ProgressBar> {
+	bar: Rc<B>,
+	/// How many of the group's units a step that has its own unit advances by per item
+	unit_size: usize,
+}
+
+impl<B: ProgressBar> Group<B> {
+	/// Set how much each item counted by a step with its own unit is worth
+	///
+	/// E.g. with bytes as the group's unit, the sector size,
+	/// so that erasing a sector counts as much as writing one.
+	/// Steps without a unit are assumed to count in the group's unit.
+	pub fn with_unit_size(mut self, size: usize) -> Self {
+		self.unit_size = size;
+		self
+	}
+
+	/// Mark the whole operation as complete
+	pub fn finish(&self) {
+		self.bar.finish();
+	}
+}
+
+impl<B: ProgressBar> ProgressBarFactory for Group<B> {
+	type BarType = GroupStep<B>;
+	fn create(&self, _len: usize, _msg: &'static str, unit: Option<&'static str>) -> Self::BarType {
+		GroupStep {
+			group: Rc::clone(&self.bar),
+			pos: Cell::new(0),
+			scale: if unit.is_some() { self.unit_size } else { 1 },
+		}
+	}
+}
+
+/// One step of a [`Group`]
+pub struct GroupStep<B: ProgressBar> {
+	group: Rc<B>,
+	/// Progress in the step's own unit
+	pos: Cell<usize>,
+	/// How many of the group's units each of the step's is worth
+	scale: usize,
+}
+
+impl<B: ProgressBar> ProgressBar for GroupStep<B> {
+	fn inc(&self, n: usize) {
+		self.pos.set(self.pos.get() + n);
+		self.group.inc(n * self.scale);
+	}
+	fn set(&self, n: usize) {
+		// The shared bar can only move forward
+		let pos = self.pos.get();
+		if n > pos {
+			self.inc(n - pos);
+		}
+	}
+	fn finish(&self) {}
+	fn abandon(&self, msg: &str) {
+		// A failed step fails the whole operation
+		self.group.abandon(msg);
+	}
+}
+
+/// Streaming CRC-32 (the IEEE polynomial, as used by zlib)
+#[derive(Clone, Debug)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+	pub fn new() -> Self {
+		Self(!0)
+	}
+
+	/// Feed more data into the checksum
+	pub fn update(&mut self, data: &[u8]) {
+		for &b in data {
+			let mut c = (self.0 ^ b as u32) & 0xFF;
+			for _ in 0..8 {
+				c = if c & 1 != 0 {
+					0xEDB8_8320 ^ (c >> 1)
+				} else {
+					c >> 1
+				};
+			}
+			self.0 = (self.0 >> 8) ^ c;
+		}
+	}
+
+	/// The checksum of all the data fed so far
+	pub fn value(&self) -> u32 {
+		!self.0
+	}
+}
+
+impl Default for Crc32 {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl std::io::Write for Crc32 {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.update(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
 }